@@ -1,14 +1,16 @@
 // use protoc_rust::Customize;
 // compile the proto spec
 fn main() {
-    let tbb_proto_path = "src/utils/tbb.proto";
+    let proto_paths = ["src/utils/tbb.proto", "src/utils/memtrace.proto"];
     // only re-compile if it has been changed
-    println!("cargo:rerun-if-changed={}", tbb_proto_path);
+    for proto_path in &proto_paths {
+        println!("cargo:rerun-if-changed={}", proto_path);
+    }
     // recompile using protoc_rust and geterate .rs file
     protoc_rust::Codegen::new()
         .out_dir("src/utils/")
-        .inputs(&[tbb_proto_path])
+        .inputs(&proto_paths)
         .include("src/utils/")
         .run()
         .expect("protoc compiling error");
-}
\ No newline at end of file
+}
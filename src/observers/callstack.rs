@@ -0,0 +1,378 @@
+//! Shadow call stack
+//!
+//! `CallStackHook` follows the Call, CallInd and Return operations of every
+//! executed instruction to maintain a shadow call stack. Tail calls are
+//! detected as branches to a known function entry, and longjmp-style
+//! unwinding as returns that land on the return address of a deeper frame
+//! or that move the stack pointer above the frames recorded on the stack.
+//!
+//! The stack is shared through a `CallStack` handle, so other hooks (e.g. a
+//! `Watchpoint` callback) can ask for the current call chain. Instruction
+//! counts per stack are kept in the folded format used by flame graph tools.
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use fugue::ir::{
+    Address,
+    il::pcode::{PCode, PCodeOp},
+};
+use fugue::bytes::Order;
+use fuguex::concrete::hooks::{ClonableHookConcrete, HookConcrete};
+use fuguex::hooks::types::{HookStepAction, HookOutcome, Error};
+use fuguex::state::{
+    AsState,
+    pcode::PCodeState, StateOps};
+use fuguex::machine::StepState;
+
+use crate::observers::trace::TraceError;
+
+const MAX_DEPTH: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Frame {
+    pub function: u64,              // Entry of the function
+    pub call_site: u64,             // Address of the call instruction, 0 for the root frame
+    pub return_address: u64,
+    pub stack_pointer: Option<u64>, // Stack pointer when the function was entered
+    pub entered_at: u64,            // Instruction count when the function was entered
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackEvent {
+    Call(Frame),
+    Return(Vec<Frame>),             // Popped frames, more than one when unwinding
+    TailCall { replaced: Frame, function: u64 },
+}
+
+// Control transfer made by the previous instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    None,
+    Call { site: u64, return_address: u64 },
+    Return,
+    Branch,
+}
+
+/// Shadow call stack, driven by `step` for each executed instruction
+#[derive(Debug, Clone)]
+pub struct ShadowCallStack {
+    frames: Vec<Frame>,
+    functions: HashSet<u64>,        // Known function entries, from calls or symbols
+    pending: Transfer,
+    fallthrough: u64,
+    icount: u64,
+
+    stack_ids: HashMap<Vec<u64>, usize>,    // Interned stacks of function entries
+    stack_counts: Vec<u64>,                 // Instruction count per interned stack
+    current_id: Option<usize>,
+}
+
+impl Default for ShadowCallStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShadowCallStack {
+    pub fn new() -> Self {
+        Self {
+            frames: Vec::new(),
+            functions: HashSet::new(),
+            pending: Transfer::None,
+            fallthrough: 0,
+            icount: 0,
+            stack_ids: HashMap::new(),
+            stack_counts: Vec::new(),
+            current_id: None,
+        }
+    }
+
+    /// Register a function entry, e.g. from a symbol table, to detect tail calls into it
+    pub fn add_function(&mut self, entry: u64) {
+        self.functions.insert(entry);
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn current_function(&self) -> Option<u64> {
+        self.frames.last().map(|frame| frame.function)
+    }
+
+    pub fn icount(&self) -> u64 {
+        self.icount
+    }
+
+    pub fn is_function(&self, address: u64) -> bool {
+        self.functions.contains(&address)
+    }
+
+    fn push(&mut self, frame: Frame) -> Option<StackEvent> {
+        if self.frames.len() >= MAX_DEPTH {
+            log::warn!("Call stack deeper than {} frames, call at {:#x} ignored", MAX_DEPTH, frame.call_site);
+            return None;
+        }
+        self.functions.insert(frame.function);
+        self.frames.push(frame);
+        self.current_id = None;
+        Some(StackEvent::Call(frame))
+    }
+
+    fn pop_to(&mut self, depth: usize) -> Option<StackEvent> {
+        if depth >= self.frames.len() {
+            return None;
+        }
+        let popped = self.frames.split_off(depth);
+        self.current_id = None;
+        Some(StackEvent::Return(popped))
+    }
+
+    // Pop the frames whose stack has been discarded, i.e. entered with a lower stack pointer
+    fn unwind_to_stack_pointer(&mut self, stack_pointer: Option<u64>) -> Option<StackEvent> {
+        let stack_pointer = stack_pointer?;
+        let depth = self.frames.iter()
+            .position(|frame| frame.call_site != 0 && frame.stack_pointer.map_or(false, |sp| sp < stack_pointer))?;
+        self.pop_to(depth)
+    }
+
+    fn resolve(&mut self, address: u64, stack_pointer: Option<u64>) -> Option<StackEvent> {
+        match self.pending {
+            Transfer::Call { site, return_address } => {
+                if address == return_address {
+                    // Conditional call not taken
+                    return None;
+                }
+                self.push(Frame {
+                    function: address,
+                    call_site: site,
+                    return_address,
+                    stack_pointer,
+                    entered_at: self.icount,
+                })
+            },
+            Transfer::Return => {
+                // Return to the closest frame expecting this address, skipping unwound frames
+                if let Some(depth) = self.frames.iter().rposition(|frame| frame.return_address == address && frame.call_site != 0) {
+                    return self.pop_to(depth);
+                }
+                if address == self.fallthrough {
+                    // Conditional return not taken
+                    return None;
+                }
+                // longjmp-style return to a frame we never saw the call for
+                log::trace!("Unmatched return to {:#x}", address);
+                self.unwind_to_stack_pointer(stack_pointer)
+            },
+            Transfer::Branch => {
+                if address == self.fallthrough {
+                    return None;
+                }
+                let top = *self.frames.last()?;
+                if self.functions.contains(&address) && address != top.function {
+                    // Tail call: the callee returns straight to our caller
+                    self.frames.last_mut().unwrap().function = address;
+                    self.current_id = None;
+                    return Some(StackEvent::TailCall { replaced: top, function: address });
+                }
+                self.unwind_to_stack_pointer(stack_pointer)
+            },
+            Transfer::None => None,
+        }
+    }
+
+    /// Update the stack before the instruction at `address` is executed
+    /// stack_pointer: current value of the stack pointer, enables the unwinding heuristic
+    pub fn step(&mut self, address: u64, pcode: &PCode, stack_pointer: Option<u64>) -> Option<StackEvent> {
+        self.icount += 1;
+        if self.frames.is_empty() {
+            // Root frame for the code executed first
+            self.functions.insert(address);
+            self.frames.push(Frame {
+                function: address,
+                call_site: 0,
+                return_address: 0,
+                stack_pointer,
+                entered_at: self.icount,
+            });
+            self.current_id = None;
+        }
+
+        let event = self.resolve(address, stack_pointer);
+
+        // Remember what this instruction may do, it is resolved at the next step
+        self.fallthrough = address + pcode.length() as u64;
+        self.pending = Transfer::None;
+        for op in pcode.operations() {
+            match op {
+                PCodeOp::Call { .. } | PCodeOp::ICall { .. } => {
+                    self.pending = Transfer::Call { site: address, return_address: self.fallthrough };
+                    break;
+                },
+                PCodeOp::Return { .. } => {
+                    self.pending = Transfer::Return;
+                    break;
+                },
+                PCodeOp::Branch { .. } | PCodeOp::CBranch { .. } | PCodeOp::IBranch { .. } => {
+                    self.pending = Transfer::Branch;
+                },
+                _ => (),
+            }
+        }
+
+        self.count_instruction();
+        event
+    }
+
+    fn count_instruction(&mut self) {
+        let id = match self.current_id {
+            Some(id) => id,
+            None => {
+                let stack: Vec<u64> = self.frames.iter().map(|frame| frame.function).collect();
+                let next_id = self.stack_counts.len();
+                let id = *self.stack_ids.entry(stack).or_insert(next_id);
+                if id == next_id {
+                    self.stack_counts.push(0);
+                }
+                self.current_id = Some(id);
+                id
+            }
+        };
+        self.stack_counts[id] += 1;
+    }
+
+    /// Instruction count per stack, outermost function first
+    pub fn stack_counts(&self) -> Vec<(Vec<u64>, u64)> {
+        self.stack_ids.iter()
+            .map(|(stack, id)| (stack.clone(), self.stack_counts[*id]))
+            .collect()
+    }
+
+    /// Write the stacks in folded format, e.g. `main;HAL_Init;HAL_GetTick 1234`
+    /// symbols: names of the functions, unnamed functions are written as addresses
+    pub fn write_folded<W: Write + ?Sized>(&self, writer: &mut W, symbols: &HashMap<u64, String>) -> std::io::Result<()> {
+        let mut lines: Vec<(String, u64)> = self.stack_counts().into_iter()
+            .filter(|(_, count)| *count != 0)
+            .map(|(stack, count)| {
+                let names: Vec<String> = stack.iter()
+                    .map(|function| symbols.get(function).cloned().unwrap_or_else(|| format!("{:#x}", function)))
+                    .collect();
+                (names.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+        for (stack, count) in lines {
+            writeln!(writer, "{} {}", stack, count)?;
+        }
+        writer.flush()
+    }
+}
+
+/// Handle on the shadow call stack of a CallStackHook
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    stack: Arc<Mutex<ShadowCallStack>>,
+}
+
+impl CallStack {
+    /// Copy of the current frames, outermost first
+    pub fn current(&self) -> Vec<Frame> {
+        self.stack.lock().frames().to_vec()
+    }
+
+    /// Call sites of the current frames, innermost first, e.g. to report who accessed a peripheral
+    pub fn backtrace(&self) -> Vec<u64> {
+        self.stack.lock().frames().iter().rev()
+            .filter(|frame| frame.call_site != 0)
+            .map(|frame| frame.call_site)
+            .collect()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.stack.lock().depth()
+    }
+
+    pub fn add_function(&self, entry: u64) {
+        self.stack.lock().add_function(entry);
+    }
+
+    pub fn write_folded<W: Write + ?Sized>(&self, writer: &mut W, symbols: &HashMap<u64, String>) -> std::io::Result<()> {
+        self.stack.lock().write_folded(writer, symbols)
+    }
+
+    /// Run function on the shadow call stack
+    pub fn with_stack<F, R>(&self, f: F) -> R
+    where F: FnOnce(&ShadowCallStack) -> R {
+        f(&*self.stack.lock())
+    }
+}
+
+/// Call stack observer
+/// S: State
+/// O: Order
+pub struct CallStackHook<S, O> {
+    stack: CallStack,
+    state: PhantomData<S>,
+    order: PhantomData<O>,
+}
+
+// NOTE: manual implementation avoids adding the trait bounds `S: Clone, O: Clone`.
+impl<S, O> Clone for CallStackHook<S, O> {
+    fn clone(&self) -> Self {
+        Self {
+            stack: self.stack.clone(),
+            state: PhantomData,
+            order: PhantomData,
+        }
+    }
+}
+
+impl<S, O> CallStackHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    pub fn new() -> (CallStack, Self) {
+        let stack = CallStack::default();
+        let observer = Self {
+            stack: stack.clone(),
+            state: PhantomData,
+            order: PhantomData,
+        };
+        (stack, observer)
+    }
+}
+
+impl<S: 'static, O> HookConcrete for CallStackHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    type State = S;
+    type Error = TraceError;
+    type Outcome = String;
+
+    fn hook_architectural_step(&mut self, state: &mut Self::State, address: &Address, step_state: &StepState)
+        -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>
+    {
+        let stack_pointer = state.state_ref().stack_pointer_value().ok().map(u64::from);
+        if let Some(event) = self.stack.stack.lock().step(u64::from(address), step_state.operations(), stack_pointer) {
+            log::trace!("Call stack: {:?}", event);
+        }
+        Ok(HookStepAction::Pass.into())
+    }
+}
+
+impl<S: 'static, O> ClonableHookConcrete for CallStackHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+}
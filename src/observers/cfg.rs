@@ -0,0 +1,321 @@
+//! Dynamic control-flow graph
+//!
+//! `get_cfg_trace_obs` returns a `TraceHook` that rebuilds the control-flow
+//! graph of the executed code: nodes are basic blocks, split wherever
+//! execution enters or leaves straight-line code, and edges carry their
+//! kind and the number of times they were taken. A block entered in its
+//! middle (e.g. a loop head first reached by fallthrough) is split there, so
+//! blocks never overlap. A graph can also be rebuilt from a collected TBB
+//! trace, with less precise edge kinds.
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use petgraph::graphmap::DiGraphMap;
+use serde::{Deserialize, Serialize};
+use fugue::ir::il::pcode::PCodeOp;
+use fugue::bytes::Order;
+use fuguex::state::pcode::PCodeState;
+
+use crate::observers::trace::{TraceCollectorError, TraceHook, TraceCollector};
+use crate::utils::tbb;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    Call,
+    Return,
+    Indirect,   // Indirect branch or call, or a transfer without branch (e.g. an interrupt)
+}
+
+impl EdgeKind {
+    fn dot_style(&self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "color=black",
+            EdgeKind::Jump => "color=blue",
+            EdgeKind::Call => "color=darkgreen, style=dashed",
+            EdgeKind::Return => "color=red, style=dashed",
+            EdgeKind::Indirect => "color=orange, style=bold",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BasicBlock {
+    pub start: u64,
+    pub end: u64,           // Exclusive
+    pub instructions: u32,
+    pub executions: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CfgEdge {
+    pub source: u64,        // Start of the source block
+    pub target: u64,        // Start of the target block
+    pub kind: EdgeKind,
+    pub count: u64,
+}
+
+/// Weight of the edges of the petgraph graph
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdgeWeight {
+    pub kind: EdgeKind,
+    pub count: u64,
+}
+
+/// Serializable form of a DynamicCfg
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CfgData {
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<CfgEdge>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DynamicCfg {
+    graph: DiGraphMap<u64, EdgeWeight>,
+    blocks: BTreeMap<u64, BasicBlock>,
+}
+
+impl DynamicCfg {
+    pub fn blocks(&self) -> impl Iterator<Item = &BasicBlock> {
+        self.blocks.values()
+    }
+
+    pub fn block(&self, start: u64) -> Option<&BasicBlock> {
+        self.blocks.get(&start)
+    }
+
+    pub fn edges(&self) -> impl Iterator<Item = CfgEdge> + '_ {
+        self.graph.all_edges().map(|(source, target, weight)| CfgEdge {
+            source,
+            target,
+            kind: weight.kind,
+            count: weight.count,
+        })
+    }
+
+    /// Underlying graph, nodes are block start addresses
+    pub fn graph(&self) -> &DiGraphMap<u64, EdgeWeight> {
+        &self.graph
+    }
+
+    fn add_block(&mut self, block: BasicBlock) {
+        self.graph.add_node(block.start);
+        let entry = self.blocks.entry(block.start).or_insert(BasicBlock { executions: 0, ..block });
+        entry.end = entry.end.max(block.end);
+        entry.instructions = entry.instructions.max(block.instructions);
+        entry.executions += block.executions;
+    }
+
+    fn add_edge(&mut self, source: u64, target: u64, kind: EdgeKind) {
+        self.add_edge_count(source, target, kind, 1);
+    }
+
+    fn add_edge_count(&mut self, source: u64, target: u64, kind: EdgeKind, count: u64) {
+        self.graph.add_node(source);
+        self.graph.add_node(target);
+        if let Some(weight) = self.graph.edge_weight_mut(source, target) {
+            weight.count += count;
+        } else {
+            self.graph.add_edge(source, target, EdgeWeight { kind, count });
+        }
+    }
+
+    // Block strictly containing `address`, i.e. not starting there
+    fn block_containing(&self, address: u64) -> Option<BasicBlock> {
+        self.blocks.range(..address).next_back()
+            .map(|(_, block)| *block)
+            .filter(|block| address < block.end)
+    }
+
+    // Split `block` at `address`: the head falls through to the tail, which
+    // takes the outgoing edges of the block
+    fn split_block(&mut self, block: BasicBlock, address: u64, head_instructions: u32, tail_instructions: u32) {
+        self.blocks.insert(block.start, BasicBlock { end: address, instructions: head_instructions, ..block });
+        self.add_block(BasicBlock { start: address, instructions: tail_instructions, ..block });
+
+        let outgoing: Vec<(u64, EdgeWeight)> = self.graph.edges(block.start)
+            .map(|(_, target, weight)| (target, *weight))
+            .collect();
+        for (target, weight) in outgoing {
+            self.graph.remove_edge(block.start, target);
+            self.add_edge_count(address, target, weight.kind, weight.count);
+        }
+        self.add_edge_count(block.start, address, EdgeKind::Fallthrough, block.executions);
+    }
+
+    /// Subgraph of the blocks starting in [start, end), e.g. the range of one function
+    pub fn restrict(&self, start: u64, end: u64) -> DynamicCfg {
+        let inside = |address: u64| start <= address && address < end;
+        let mut cfg = DynamicCfg::default();
+        for block in self.blocks.values().filter(|block| inside(block.start)) {
+            cfg.graph.add_node(block.start);
+            cfg.blocks.insert(block.start, *block);
+        }
+        for (source, target, weight) in self.graph.all_edges() {
+            if inside(source) && inside(target) {
+                cfg.graph.add_edge(source, target, *weight);
+            }
+        }
+        cfg
+    }
+
+    pub fn to_data(&self) -> CfgData {
+        let mut blocks: Vec<BasicBlock> = self.blocks.values().cloned().collect();
+        blocks.sort_by_key(|block| block.start);
+        let mut edges: Vec<CfgEdge> = self.edges().collect();
+        edges.sort_by_key(|edge| (edge.source, edge.target));
+        CfgData { blocks, edges }
+    }
+
+    pub fn from_data(data: &CfgData) -> Self {
+        let mut cfg = DynamicCfg::default();
+        for block in &data.blocks {
+            cfg.graph.add_node(block.start);
+            cfg.blocks.insert(block.start, *block);
+        }
+        for edge in &data.edges {
+            cfg.graph.add_edge(edge.source, edge.target, EdgeWeight { kind: edge.kind, count: edge.count });
+        }
+        cfg
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&self.to_data())
+    }
+
+    /// Write the graph in Graphviz DOT format
+    pub fn write_dot<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+        let data = self.to_data();
+        writeln!(writer, "digraph cfg {{")?;
+        writeln!(writer, "    node [shape=box, fontname=\"monospace\"];")?;
+        for block in &data.blocks {
+            writeln!(writer, "    \"{:#x}\" [label=\"{:#x} - {:#x}\\n{} instructions, {} executions\"];",
+                block.start, block.start, block.end, block.instructions, block.executions)?;
+        }
+        for edge in &data.edges {
+            writeln!(writer, "    \"{:#x}\" -> \"{:#x}\" [label=\"{:?} ({})\", {}];",
+                edge.source, edge.target, edge.kind, edge.count, edge.kind.dot_style())?;
+        }
+        writeln!(writer, "}}")?;
+        writer.flush()
+    }
+
+    /// Rebuild a graph from a TBB trace, which only records instruction addresses
+    /// An instruction following the previous one by at most `max_instruction_size`
+    /// bytes is taken as fallthrough, any other transfer as a jump
+    pub fn from_tbb(blocks: &tbb::TBBBlocks, max_instruction_size: u64) -> Self {
+        let mut builder = CfgBuilder::default();
+        let mut last: Option<u64> = None;
+        for block in blocks.get_basic_blocks() {
+            let address = block.get_address();
+            // Assume the previous instruction ends where this one starts, when it falls through
+            let transfer = match last {
+                Some(last) if address > last && address - last <= max_instruction_size => {
+                    builder.fallthrough = address;
+                    None
+                },
+                Some(_) => Some(EdgeKind::Jump),
+                None => None,
+            };
+            builder.transfer = transfer;
+            builder.step(address, 0, None);
+            last = Some(address);
+        }
+        builder.finish()
+    }
+}
+
+/// Events of the CFG trace observer, turned into a DynamicCfg by `finish`
+#[derive(Debug, Clone, Default)]
+pub struct CfgBuilder {
+    cfg: DynamicCfg,
+    current: Option<BasicBlock>,    // Block being executed
+    fallthrough: u64,               // Address following the last instruction
+    transfer: Option<EdgeKind>,     // Kind of the transfer the last instruction may take
+    instructions: BTreeSet<u64>,    // Addresses of the executed instructions, to split blocks
+}
+
+impl CfgBuilder {
+    // length: size of the instruction, 0 if unknown
+    // transfer: kind of transfer the instruction may take
+    fn step(&mut self, address: u64, length: u64, transfer: Option<EdgeKind>) {
+        let leaves_block = self.transfer.is_some()
+            || address != self.fallthrough
+            || (self.cfg.blocks.contains_key(&address) && self.current.map_or(false, |block| block.start != address));
+
+        if let Some(block) = self.current {
+            if leaves_block {
+                let kind = if address == self.fallthrough {
+                    EdgeKind::Fallthrough
+                } else {
+                    self.transfer.unwrap_or(EdgeKind::Indirect)
+                };
+                self.cfg.add_block(BasicBlock { end: self.fallthrough.max(block.start), ..block });
+                self.cfg.add_edge(block.start, address, kind);
+                self.split_at(address);
+                self.current = None;
+            }
+        }
+        self.instructions.insert(address);
+
+        let block = self.current.get_or_insert(BasicBlock {
+            start: address,
+            end: address,
+            instructions: 0,
+            executions: 1,
+        });
+        block.instructions += 1;
+
+        if length != 0 {
+            self.fallthrough = address + length;
+        }
+        self.transfer = transfer;
+    }
+
+    // Split the recorded block execution entered in the middle of
+    fn split_at(&mut self, address: u64) {
+        if let Some(block) = self.cfg.block_containing(address) {
+            let head = self.instructions.range(block.start..address).count() as u32;
+            let tail = self.instructions.range(address..block.end).count() as u32;
+            self.cfg.split_block(block, address, head, tail);
+        }
+    }
+
+    /// Close the block being executed and return the graph
+    pub fn finish(mut self) -> DynamicCfg {
+        if let Some(block) = self.current.take() {
+            self.cfg.add_block(BasicBlock { end: self.fallthrough.max(block.start), ..block });
+        }
+        self.cfg
+    }
+}
+
+// Kind of the transfer an instruction may take, from its operations
+fn transfer_kind(operations: &[PCodeOp]) -> Option<EdgeKind> {
+    let mut kind = None;
+    for op in operations {
+        match op {
+            PCodeOp::Call { .. } => return Some(EdgeKind::Call),
+            PCodeOp::ICall { .. } | PCodeOp::IBranch { .. } => return Some(EdgeKind::Indirect),
+            PCodeOp::Return { .. } => return Some(EdgeKind::Return),
+            PCodeOp::Branch { .. } | PCodeOp::CBranch { .. } => kind = Some(EdgeKind::Jump),
+            _ => (),
+        }
+    }
+    kind
+}
+
+pub fn get_cfg_trace_obs<O: Order>()->
+(TraceCollector<CfgBuilder>,
+	TraceHook<PCodeState<u8, O>, O, CfgBuilder>) {
+
+	TraceHook::new_unboxed(
+		|address,
+		pcode,
+		_state: & mut PCodeState<u8, O>,
+		collector: & mut CfgBuilder | -> Result<(), TraceCollectorError>{
+			collector.step(u64::from(address), pcode.length() as u64, transfer_kind(pcode.operations()));
+			Ok(())
+
+	})
+}
@@ -0,0 +1,319 @@
+//! AFL-style edge coverage
+//!
+//! `EdgeCoverageHook` hashes every (previous block, current block) pair into
+//! a fixed-size bitmap of hit counters, the same way AFL instruments
+//! binaries. The bitmap is shared between the hook and the fuzzer through
+//! `CoverageMap`, and `VirginMap` tells whether a run reached new coverage.
+//!
+//! Only the counters are shared: each hook keeps its own previous block, so
+//! emulators recording into the same map hash their own edges. A hook starts
+//! from no previous block again after the map is `reset`.
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use fugue::ir::{
+    Address,
+    il::pcode::PCodeOp,
+};
+use fugue::bytes::Order;
+use fuguex::concrete::hooks::{ClonableHookConcrete, HookConcrete};
+use fuguex::hooks::types::{HookStepAction, HookOutcome, Error};
+use fuguex::state::{
+    AsState,
+    pcode::PCodeState, StateOps};
+use fuguex::machine::StepState;
+
+pub const DEFAULT_MAP_SIZE: usize = 1 << 16;
+
+// Hit counts are bucketed the way AFL does it:
+// 0, 1, 2, 3, 4-7, 8-15, 16-31, 32-127, 128+
+const fn count_class_lookup() -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = match i {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            3 => 4,
+            4..=7 => 8,
+            8..=15 => 16,
+            16..=31 => 32,
+            32..=127 => 64,
+            _ => 128,
+        };
+        i += 1;
+    }
+    table
+}
+
+static COUNT_CLASS_LOOKUP: [u8; 256] = count_class_lookup();
+
+/// Map a raw hit count to its bucket
+#[inline]
+pub fn classify_count(count: u8) -> u8 {
+    COUNT_CLASS_LOOKUP[count as usize]
+}
+
+/// Outcome of comparing a run against the virgin map
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum NewCoverage {
+    None,
+    NewHitCount,    // A known edge was hit a different number of times
+    NewEdge,        // An edge that has never been hit before
+}
+
+impl NewCoverage {
+    pub fn is_interesting(&self) -> bool {
+        !matches!(self, NewCoverage::None)
+    }
+}
+
+/// Shared bitmap of edge hit counters
+/// Cloning it gives another handle to the same map
+#[derive(Clone)]
+pub struct CoverageMap {
+    map: Arc<[AtomicU8]>,
+    resets: Arc<AtomicU64>,     // Number of resets, hooks restart their edges when it changes
+}
+
+impl std::fmt::Debug for CoverageMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CoverageMap")
+            .field("size", &self.size())
+            .field("edges_hit", &self.count_edges())
+            .finish()
+    }
+}
+
+impl Default for CoverageMap {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAP_SIZE)
+    }
+}
+
+impl CoverageMap {
+    /// size: number of counters, must be a power of two
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two(), "coverage map size ({}) must be a power of two", size);
+        let map: Vec<AtomicU8> = (0..size).map(|_| AtomicU8::new(0)).collect();
+        Self {
+            map: map.into(),
+            resets: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.map.len()
+    }
+
+    #[inline]
+    fn hit(&self, index: usize) {
+        // Never let a counter wrap back to zero, otherwise the edge looks unvisited
+        if self.map[index].fetch_add(1, Ordering::Relaxed) == u8::MAX {
+            self.map[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Record the edge from `prev` to the block, and return the location to pass as the next `prev`
+    #[inline]
+    fn record_block(&self, address: u64, prev: u64) -> u64 {
+        let mask = (self.map.len() - 1) as u64;
+        let current = ((address >> 4) ^ (address << 8)) & mask;
+        self.hit((current ^ prev) as usize);
+        current >> 1
+    }
+
+    fn resets(&self) -> u64 {
+        self.resets.load(Ordering::Acquire)
+    }
+
+    /// Clear the counters before the next run
+    pub fn reset(&self) {
+        for counter in self.map.iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.resets.fetch_add(1, Ordering::Release);
+    }
+
+    /// Raw hit counters
+    pub fn counts(&self) -> Vec<u8> {
+        self.map.iter().map(|counter| counter.load(Ordering::Relaxed)).collect()
+    }
+
+    /// Hit counters mapped to their buckets
+    pub fn classified(&self) -> Vec<u8> {
+        self.map.iter().map(|counter| classify_count(counter.load(Ordering::Relaxed))).collect()
+    }
+
+    pub fn count_edges(&self) -> usize {
+        self.map.iter().filter(|counter| counter.load(Ordering::Relaxed) != 0).count()
+    }
+
+    /// Add the counters of another map (e.g. from a parallel run) to this one
+    /// Either map may be in use by running hooks: each counter is added atomically
+    pub fn merge(&self, other: &CoverageMap) {
+        assert_eq!(self.size(), other.size(), "cannot merge coverage maps of different sizes");
+        for (mine, theirs) in self.map.iter().zip(other.map.iter()) {
+            let count = theirs.load(Ordering::Relaxed);
+            if count != 0 {
+                let _ = mine.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |mine| Some(mine.saturating_add(count)));
+            }
+        }
+    }
+}
+
+/// Buckets that have not been seen yet, across all runs
+#[derive(Debug, Clone)]
+pub struct VirginMap {
+    bits: Vec<u8>,
+}
+
+impl VirginMap {
+    pub fn new(size: usize) -> Self {
+        Self {
+            bits: vec![0xff; size],
+        }
+    }
+
+    /// Check a run for new coverage and mark its buckets as seen
+    pub fn has_new_bits(&mut self, map: &CoverageMap) -> NewCoverage {
+        assert_eq!(self.bits.len(), map.size(), "virgin map and coverage map sizes differ");
+        let mut result = NewCoverage::None;
+        for (virgin, counter) in self.bits.iter_mut().zip(map.map.iter()) {
+            let count = counter.load(Ordering::Relaxed);
+            if count == 0 {
+                continue;
+            }
+            let bucket = classify_count(count);
+            if bucket & *virgin != 0 {
+                if *virgin == 0xff {
+                    result = NewCoverage::NewEdge;
+                } else if result == NewCoverage::None {
+                    result = NewCoverage::NewHitCount;
+                }
+                *virgin &= !bucket;
+            }
+        }
+        result
+    }
+
+    /// Combine the coverage seen by another (e.g. parallel) fuzzer instance
+    pub fn merge(&mut self, other: &VirginMap) {
+        assert_eq!(self.bits.len(), other.bits.len(), "cannot merge virgin maps of different sizes");
+        for (mine, theirs) in self.bits.iter_mut().zip(other.bits.iter()) {
+            *mine &= *theirs;
+        }
+    }
+
+    pub fn count_edges(&self) -> usize {
+        self.bits.iter().filter(|bits| **bits != 0xff).count()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
+/// Edge coverage observer
+/// S: State
+/// O: Order
+pub struct EdgeCoverageHook<S, O> {
+    map: CoverageMap,
+    prev_location: u64,     // Hashed previous block, shifted
+    resets: u64,            // Resets of the map seen by the hook
+    fallthrough: u64,       // Address following the last instruction
+    block_ended: bool,      // Whether the last instruction could transfer control
+    state: PhantomData<S>,
+    order: PhantomData<O>,
+}
+
+// NOTE: manual implementation avoids adding the trait bounds `S: Clone, O: Clone`.
+impl<S, O> Clone for EdgeCoverageHook<S, O> {
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
+            prev_location: self.prev_location,
+            resets: self.resets,
+            fallthrough: self.fallthrough,
+            block_ended: self.block_ended,
+            state: PhantomData,
+            order: PhantomData,
+        }
+    }
+}
+
+impl<S, O> EdgeCoverageHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    pub fn new(map_size: usize) -> (CoverageMap, Self) {
+        Self::with_map(CoverageMap::new(map_size))
+    }
+
+    /// Record into an existing map, e.g. one shared by several emulators
+    pub fn with_map(map: CoverageMap) -> (CoverageMap, Self) {
+        let observer = Self {
+            resets: map.resets(),
+            map: map.clone(),
+            prev_location: 0,
+            fallthrough: 0,
+            block_ended: true,
+            state: PhantomData,
+            order: PhantomData,
+        };
+        (map, observer)
+    }
+
+    /// Forget the previous block, e.g. when the emulator is restarted without resetting the map
+    pub fn reset(&mut self) {
+        self.prev_location = 0;
+        self.fallthrough = 0;
+        self.block_ended = true;
+        self.resets = self.map.resets();
+    }
+}
+
+impl<S: 'static, O> HookConcrete for EdgeCoverageHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    type State = S;
+    type Error = std::convert::Infallible;
+    type Outcome = String;
+
+    fn hook_architectural_step(&mut self, _state: &mut Self::State, address: &Address, step_state: &StepState)
+        -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>
+    {
+        let address = u64::from(address);
+        // A reset of the map starts a new run
+        if self.resets != self.map.resets() {
+            self.reset();
+        }
+        // Only a block entry creates an edge, straight-line code is skipped
+        if self.block_ended || address != self.fallthrough {
+            self.prev_location = self.map.record_block(address, self.prev_location);
+        }
+
+        let pcode = step_state.operations();
+        self.fallthrough = address + pcode.length() as u64;
+        self.block_ended = pcode.operations().iter().any(|op| matches!(op,
+            PCodeOp::Branch { .. }
+            | PCodeOp::CBranch { .. }
+            | PCodeOp::IBranch { .. }
+            | PCodeOp::Call { .. }
+            | PCodeOp::ICall { .. }
+            | PCodeOp::Return { .. }));
+
+        Ok(HookStepAction::Pass.into())
+    }
+}
+
+impl<S: 'static, O> ClonableHookConcrete for EdgeCoverageHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+}
@@ -1,5 +1,8 @@
-use crate::observers::solver::ConstraintSolver;
+use crate::observers::solver::{
+    ConcretizationStats, ConstraintSolver, Objective, Query, QueryCacheStats,
+    SolveOutcome, SolverError, SolverLimits, UnsupportedOpPolicy, ValuesOutcome};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::RwLock;
 use std::marker::PhantomData;
 use std::sync::Arc;
@@ -8,28 +11,70 @@ use fugue::ir::{
     il::ecode::Location,
     il::pcode::{Operand, PCodeOp, }
 };
-use fugue::bytes::{ByteCast, BE, LE};
+use fugue::bytes::{BE, LE};
+use fugue::bv::BitVec;
 use fuguex::concrete::hooks::{ClonableHookConcrete, HookConcrete};
 use fuguex::hooks::types::{HookStepAction, HookOutcome, Error};
 use fuguex::state::{
     State,
     pcode::PCodeState, StateOps};
 use fuguex::machine::StepState;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::registers;
 
     
 #[derive(Debug, Clone)]
 pub struct SolvingResult {
     pub target_addr: Address,
-    pub value: u64,
-    pub size: usize,
+    pub value: BitVec,
+    pub size: usize,            // In bytes
 }
 
 impl std::cmp::PartialEq for SolvingResult {
     fn eq(&self, eq2: &SolvingResult) -> bool {
-        self.target_addr == eq2.target_addr && self.value == eq2.value
+        self.target_addr == eq2.target_addr && self.value == eq2.value && self.size == eq2.size
     }
 }
 
+/// A peripheral value provided by the solver or the result cache
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SolveEvent {
+    pub icount: u64,            // Event counter (executed instructions) when the value was written
+    pub pc: u64,                // Instruction that loaded the register
+    pub address: u64,
+    pub size: usize,
+    pub value: Option<String>,  // Hexadecimal, None if the solver failed
+    pub cached: bool,
+}
+
+/// Outcomes of the queries made by a DummyPeripheral
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SolveStats {
+    pub queries: u64,
+    pub solved: u64,
+    pub failed: u64,        // Solver errors
+    pub unknown: u64,       // Queries over budget, the fallback value was written
+    pub original_nodes: u64,    // Nodes of the queries before simplification
+    pub nodes: u64,             // Nodes of the queries solved
+}
+
+/// Value written for the register when several satisfy the loop condition
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValueSelection {
+    /// The model given by the solver
+    #[default]
+    Any,
+    /// The smallest value, unsigned
+    Minimum,
+    /// The largest value, unsigned
+    Maximum,
+    /// Up to `max_values` distinct values, written in turn on the successive solves of the register
+    Enumerate { max_values: usize },
+}
+
+
+const DEFAULT_MAX_CALL_DEPTH: u32 = 2;
 
 #[derive(Debug)]
 pub struct DummyPeripheral<S, E> {
@@ -42,9 +87,19 @@ pub struct DummyPeripheral<S, E> {
     solving_started: bool,
     solving_results_cache_enable: bool,
     solving_results: Arc<RwLock<HashMap<Address, SolvingResult>>>,
+    solve_events: Arc<RwLock<Vec<SolveEvent>>>,
+    solve_stats: Arc<RwLock<SolveStats>>,
+    fallback_value: u128,           // Written when a query is over budget
+    query_dump_dir: Option<PathBuf>,
+    value_selection: ValueSelection,
+    enumerated_values: Arc<RwLock<HashMap<Address, Vec<BitVec>>>>,   // Last values enumerated per register
+    enumeration_turns: HashMap<Address, usize>,     // Solves of each register under ValueSelection::Enumerate
     solver_default_vars: HashMap<String, u128>, // <name, values>
     solver: ConstraintSolver<LE>,
     forgive_jump: u32,
+    call_depth: u32,                // Calls entered since the start of the solving
+    max_call_depth: u32,            // Calls deeper than this are executed concretely
+    concrete_depth: Option<u32>,    // Depth of the callee being executed concretely
 
     last_mem_read_event: (Address, Address, usize, u128),  // PC, ReadAddress, size in byte, EventCounter
     last_reg_write_event: (Address, u128),
@@ -64,7 +119,17 @@ impl <S, E> Clone for DummyPeripheral<S, E> {
             last_mem_read_event: self.last_mem_read_event.clone(),     // (The address that it read data from, event_counter)
             last_reg_write_event: self.last_reg_write_event.clone(),
             solving_results: self.solving_results.clone(),
+            solve_events: self.solve_events.clone(),
+            solve_stats: self.solve_stats.clone(),
+            fallback_value: self.fallback_value,
+            query_dump_dir: self.query_dump_dir.clone(),
+            value_selection: self.value_selection,
+            enumerated_values: self.enumerated_values.clone(),
+            enumeration_turns: self.enumeration_turns.clone(),
             forgive_jump: self.forgive_jump,
+            call_depth: self.call_depth,
+            max_call_depth: self.max_call_depth,
+            concrete_depth: self.concrete_depth,
 
             solver: self.solver.clone(),
         }
@@ -87,7 +152,17 @@ where S: State,
             last_mem_read_event: (Address::from(0u32), Address::from(0u32), 0, 0),     // (The address that it read data from, event_counter)
             last_reg_write_event: (Address::from(0u32), 0),
             solving_results: Arc::new(RwLock::new(HashMap::<Address, SolvingResult>::new())),
+            solve_events: Arc::new(RwLock::new(Vec::new())),
+            solve_stats: Arc::new(RwLock::new(SolveStats::default())),
+            fallback_value: 0,
+            query_dump_dir: None,
+            value_selection: ValueSelection::default(),
+            enumerated_values: Arc::new(RwLock::new(HashMap::new())),
+            enumeration_turns: HashMap::new(),
             forgive_jump: 0,
+            call_depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            concrete_depth: None,
 
             solver: ConstraintSolver::new(),
 
@@ -99,10 +174,131 @@ where S: State,
         self.solving_results_cache_enable = enable;
     }
 
+    /// Loads from the range start the solving, and are the only symbolic inputs of the solver
     pub fn add_address_range<A>(&mut self, addr_range: (A, A)) where A: Into<Address> {
         // TODO: initialize memory
         let (addr_start, addr_end) = addr_range;
-        self.address_range_list.push((addr_start.into(), addr_end.into()));
+        let (addr_start, addr_end) = (addr_start.into(), addr_end.into());
+        self.address_range_list.push((addr_start, addr_end));
+        self.solver.mark_symbolic(addr_start, addr_end);
+    }
+
+    /// Follow the data flow through calls made while solving, up to `depth` nested calls
+    /// Deeper callees are executed concretely: their results lose any dependence on the
+    /// peripheral, as do the results of callees doing work unsupported by the solver
+    pub fn set_max_call_depth(&mut self, depth: u32) {
+        self.max_call_depth = depth;
+    }
+
+    /// Concretize the operations the solver cannot model instead of abandoning the solving
+    pub fn set_unsupported_op_policy(&mut self, policy: UnsupportedOpPolicy) {
+        self.solver.set_unsupported_policy(policy);
+    }
+
+    pub fn concretization_stats(&self) -> ConcretizationStats {
+        self.solver.concretization_stats()
+    }
+
+    /// Time, memory and expression size budgets of each query
+    /// The memory budget is the growth of the memory of the whole process during the query,
+    /// a guard against runaway queries rather than an accounting of their allocations
+    pub fn set_solver_limits(&mut self, limits: SolverLimits) {
+        self.solver.set_limits(limits);
+    }
+
+    /// Value given to the register when a query is over budget
+    /// Queries have a 10 s timeout by default (see `SolverLimits::default`): slow queries get
+    /// this value instead of being waited for, unless `set_solver_limits` removes the timeout
+    pub fn set_fallback_value(&mut self, value: u128) {
+        self.fallback_value = value;
+    }
+
+    /// Write each query as SMT-LIB2 to `dir`, in `query_<n>_pc_<pc>_addr_<address>.smt2`
+    /// pc is the instruction that loaded the register at address
+    pub fn set_query_dump_dir<P: AsRef<Path>>(&mut self, dir: Option<P>) {
+        self.query_dump_dir = dir.map(|dir| dir.as_ref().to_path_buf());
+    }
+
+    fn dump_query(&self, query: &Query, pc: Address, address: Address) {
+        if let Some(dir) = &self.query_dump_dir {
+            let index = self.solve_stats.read().unwrap().queries;
+            let path = dir.join(format!("query_{:04}_pc_{:x}_addr_{:x}.smt2", index, u64::from(pc), u64::from(address)));
+            match std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, query.to_smtlib())) {
+                Ok(()) => log::debug!("Query written to {}", path.display()),
+                Err(e) => log::warn!("Could not write the query to {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Answer the queries made before from the solver cache, enabled by default
+    /// Unlike `enable_solving_result_cache`, the loop is still followed and the query built
+    pub fn enable_query_cache(&mut self, enable: bool) {
+        self.solver.set_query_cache(enable);
+    }
+
+    pub fn get_query_cache_stats(&self) -> QueryCacheStats {
+        self.solver.query_cache_stats()
+    }
+
+    /// How the value of the register is chosen among the values exiting the loop
+    pub fn set_value_selection(&mut self, selection: ValueSelection) {
+        self.value_selection = selection;
+    }
+
+    /// Values enumerated for each register under ValueSelection::Enumerate
+    pub fn get_enumerated_values(&self) -> Arc<RwLock<HashMap<Address, Vec<BitVec>>>> {
+        self.enumerated_values.clone()
+    }
+
+    // Apply the value selection to the register loaded from `address`
+    // The other inputs are solved again with the value selected
+    fn select_value(&mut self, query: &Query, address: Address, outcome: SolveOutcome) -> Result<SolveOutcome, SolverError> {
+        let solved = matches!(&outcome, SolveOutcome::Solved(result) if matches!(result.get(&address), Some(Some(_))));
+        if self.value_selection == ValueSelection::Any || !solved {
+            return Ok(outcome);
+        }
+        let values = match self.value_selection {
+            ValueSelection::Any => unreachable!(),
+            ValueSelection::Minimum => self.solver.optimize(query, &address, Objective::Minimize),
+            ValueSelection::Maximum => self.solver.optimize(query, &address, Objective::Maximize),
+            ValueSelection::Enumerate { max_values } => self.solver.enumerate(query, &address, max_values),
+        };
+        // The query has a model already: keep it if the selection cannot be made,
+        // e.g. the minimum of an input wider than 64 bits
+        let values = match values {
+            Ok(ValuesOutcome::Values(values)) if !values.is_empty() => values,
+            Ok(ValuesOutcome::Values(_)) => return Ok(outcome),
+            Ok(ValuesOutcome::Unknown(limit)) => {
+                log::warn!("Value selection {:?} abandoned: {}, the first model is kept", self.value_selection, limit);
+                return Ok(outcome);
+            },
+            Err(e) => {
+                log::warn!("Value selection {:?} failed: {}, the first model is kept", self.value_selection, e);
+                return Ok(outcome);
+            },
+        };
+        let value = match self.value_selection {
+            ValueSelection::Enumerate { .. } => {
+                log::info!("{} values exit the loop for {}: {:?}", values.len(), address, values);
+                let turn = self.enumeration_turns.entry(address).or_insert(0);
+                let value = values[*turn % values.len()].clone();
+                *turn += 1;
+                self.enumerated_values.write().unwrap().insert(address, values);
+                value
+            },
+            _ => values[0].clone(),
+        };
+
+        if query.inputs.len() == 1 {
+            let mut result = HashMap::new();
+            result.insert(address, Some(value));
+            return Ok(SolveOutcome::Solved(result));
+        }
+        self.solver.solve_query(&query.with_value(&address, &value)?)
+    }
+
+    pub fn get_solve_stats(&self) -> SolveStats {
+        *self.solve_stats.read().unwrap()
     }
 
     pub fn add_default_reg(&mut self, name: &str, value: u128) {
@@ -112,6 +308,45 @@ where S: State,
     pub fn get_solving_result(&self) -> Arc<RwLock<HashMap<Address, SolvingResult>>>{
         return self.solving_results.clone();
     }
+
+    /// Values written by the peripheral, in execution order
+    /// One event is recorded per poll: drain them periodically on long runs
+    pub fn get_solve_events(&self) -> Arc<RwLock<Vec<SolveEvent>>>{
+        return self.solve_events.clone();
+    }
+
+    /// Take the values written since the last drain, e.g. to export a batch of the timeline
+    pub fn drain_solve_events(&self) -> Vec<SolveEvent> {
+        std::mem::take(&mut *self.solve_events.write().unwrap())
+    }
+
+    /// Inclusive address ranges of the peripheral
+    pub fn address_ranges(&self) -> &[(Address, Address)] {
+        &self.address_range_list
+    }
+
+    fn record_solve_event(&self, pc: Address, address: Address, size: usize, value: Option<&BitVec>, cached: bool) {
+        self.solve_events.write().unwrap().push(SolveEvent {
+            icount: self.event_counter as u64,
+            pc: u64::from(pc),
+            address: u64::from(address),
+            size,
+            value: value.map(|value| Self::value_bytes(value, size, false)).map(|bytes| registers::value_to_hex::<BE>(&bytes)),
+            cached,
+        });
+    }
+
+    // Bytes of a solved value for a register of `size` bytes
+    fn value_bytes(value: &BitVec, size: usize, is_little_endian: bool) -> Vec<u8> {
+        let mut bytes = vec![0u8; size];
+        let value = value.clone().unsigned_cast(size * 8);
+        if is_little_endian {
+            value.into_bytes::<LE>(&mut bytes);
+        } else {
+            value.into_bytes::<BE>(&mut bytes);
+        }
+        bytes
+    }
 }
 
 
@@ -152,39 +387,32 @@ where S: State + StateOps,
             // Start when a value is loading from the target memory
             // Stop when a branch happens
             PCodeOp::Load {source, destination, space: _} =>{
-                let source_offset =  state.get_address(source).unwrap();
+                let source_offset = match state.get_address(source) {
+                    Ok(address) => address,
+                    Err(e) => {
+                        log::warn!("Cannot read the address of the load from {}: {:?}, solving abandoned", source, e);
+                        self.solving_started = false;
+                        return Ok(HookStepAction::Pass.into());
+                    },
+                };
                 // let source_offset = if is_little_endian {
                 //     state.state_ref().read_address::<LE>(source).unwrap()
                 // } else {
                 //     state.state_ref().read_address::<BE>(source).unwrap()
                 // };
-                println!("Observe load instruction src_offset:{} src: {}, dest: {}", source_offset, source, destination);
                 // Check if the source address falls into the peripheral range
                 for (min, max) in &self.address_range_list {
                     if *min<= source_offset && source_offset<= *max {
                         // Check if this regisiter has been solved before if have been solved, then load the previous result
                         if self.solving_results.read().unwrap().contains_key(&source_offset) && self.solving_results_cache_enable{
                             let last_result = self.solving_results.read().unwrap().get(&source_offset).unwrap().clone();
-                            log::debug!("Load cached result of memory: {} value:{:#x}", source_offset, last_result.value);
-                            
-                            if is_little_endian {
-                                let mut value_bytes = [0; 8];
-                                last_result.value.into_bytes::<LE>(&mut value_bytes);
-                                match destination.size() {
-                                    1 => {state.set_values(source_offset, &value_bytes[0..0]).unwrap();},
-                                    4 => {state.set_values(source_offset, &value_bytes[0..4]).unwrap();},
-                                    8 => {state.set_values(source_offset, &value_bytes[0..8]).unwrap();},
-                                    _ => { panic!("Unexpected value size for last load event");}
-                                };
-                            } else {
-                                let mut value_bytes = [0; 8];
-                                last_result.value.into_bytes::<BE>(&mut value_bytes);
-                                match destination.size() {
-                                    1 => {state.set_values(source_offset, &value_bytes[0..0]).unwrap();},
-                                    4 => {state.set_values(source_offset, &value_bytes[0..0]).unwrap();},
-                                    8 => {state.set_values(source_offset, &value_bytes[0..0]).unwrap();},
-                                    _ => { panic!("Unexpected value size for last load event");}
-                                };
+                            log::debug!("Load cached result of memory: {} value:{}", source_offset, last_result.value);
+                            let pc = state.program_counter_value().unwrap();
+                            self.record_solve_event(pc, source_offset.clone(), destination.size(), Some(&last_result.value), true);
+
+                            let value_bytes = Self::value_bytes(&last_result.value, destination.size(), is_little_endian);
+                            if let Err(e) = state.set_values(source_offset, &value_bytes) {
+                                log::warn!("Cannot write the cached value of {}: {:?}", source_offset, e);
                             }
                         }else {
                             // if not found in the previous result list, then start solving
@@ -198,12 +426,13 @@ where S: State + StateOps,
                             // };
                             self.last_mem_read_event = (current_pc, source_offset.clone(), destination.size(), self.event_counter);
 
-                            println!("create new solver");
                             log::debug!("create new solver");
                             self.solving_started = true;            // mark the start of solving
                             self.forgive_jump = 0;
-                            self.solver = ConstraintSolver::new();  // Create new solver
+                            self.call_depth = 0;
+                            self.concrete_depth = None;
                             self.solver.set_default_variables(&self.solver_default_vars);   // set the default variables
+                            self.solver.start_region();             // Forget the previous region
                         }
                         // don't care endian for debugging message for now
                         let pc = state.program_counter_value().unwrap();
@@ -217,13 +446,18 @@ where S: State + StateOps,
             PCodeOp::Store { source: _, destination, space: _} => {
                 // If storing sth to that memory, then it is not a reg checking loop
                 let (_pc, last_addr, _size, _last_counter) = self.last_mem_read_event;
-                let dest_addr = state.get_address(destination).unwrap();
-                if last_addr == dest_addr {
-                    self.solving_started = false;
+                match state.get_address(destination) {
+                    Ok(dest_addr) if dest_addr == last_addr => self.solving_started = false,
+                    Ok(_) => (),
+                    Err(e) => {
+                        log::warn!("Cannot read the address of the store to {}: {:?}, solving abandoned", destination, e);
+                        self.solving_started = false;
+                    },
                 }
             },
             PCodeOp::CBranch { destination, condition } =>{
-                if self.solving_started {
+                // Branches inside callees are not the polling loop
+                if self.solving_started && self.call_depth == 0 {
                     let dest_addr = if let Operand::Address { value, size: _ } = destination {
                         value.offset()
                     } else {
@@ -266,42 +500,89 @@ where S: State + StateOps,
 
                     // if loop detected then use the solver to get the expected value
                     if is_loop {
-                        if let None = self.solver.solve(state.as_ref(), condition, 0) {
-                            log::warn!("Cound not solve this value, condition {}", condition);
-                        } else{
-                            let solve_result = self.solver.solve(state.as_ref(), condition, 0)
-                            .expect("Cound not solve this value");
-                            for (k, v) in solve_result {
-                                log::info!("solving result: ({}, {:?})", k, v);
-                                // convert to BE byte array or LE byte array
-                                let mut value_bytes = [0; 8];
-                                if is_little_endian {
-                                    v.unwrap().into_bytes::<LE>(&mut value_bytes);
-                                } else {
-                                    v.unwrap().into_bytes::<BE>(&mut value_bytes);
+                        self.solve_stats.write().unwrap().queries += 1;
+                        let mut input_sizes = HashMap::new();   // Bytes of each input, for the ones without solution
+                        let outcome = self.solver.query(state.as_ref(), condition, 0).and_then(|query| {
+                            input_sizes = query.inputs.iter()
+                                .map(|(input, address)| (*address, input.bits() as usize / 8))
+                                .collect();
+                            self.dump_query(&query, pc, last_addr);
+                            log::info!("Query of {} nodes, simplified from {} ({:.1}% smaller)",
+                                query.node_count(), query.original_nodes, query.shrink_percent());
+                            let mut stats = self.solve_stats.write().unwrap();
+                            stats.original_nodes += query.original_nodes as u64;
+                            stats.nodes += query.node_count() as u64;
+                            drop(stats);
+                            let outcome = self.solver.solve_query(&query)?;
+                            self.select_value(&query, last_addr, outcome)
+                        });
+                        match outcome {
+                            Err(e) => {
+                                log::warn!("Cound not solve this value, condition {}: {}", condition, e);
+                                self.solve_stats.write().unwrap().failed += 1;
+                                self.record_solve_event(pc, last_addr, last_size, None, false);
+                            },
+                            Ok(SolveOutcome::Unknown(limit)) => {
+                                log::warn!("Solving abandoned, condition {}: {}, writing the fallback value {:#x}", condition, limit, self.fallback_value);
+                                self.solve_stats.write().unwrap().unknown += 1;
+                                let value = BitVec::from_u128(self.fallback_value, last_size * 8);
+                                self.record_solve_event(pc, last_addr, last_size, Some(&value), false);
+                                let value_bytes = Self::value_bytes(&value, last_size, is_little_endian);
+                                if let Err(e) = state.set_values(last_addr, &value_bytes) {
+                                    log::warn!("Cannot write the fallback value of {}: {:?}", last_addr, e);
                                 }
-                                // write value to state
-                                match last_size {
-                                    1 => {state.set_values(k, &value_bytes[0..0]).unwrap();},
-                                    4 => {state.set_values(k, &value_bytes[0..4]).unwrap();},
-                                    8 => {state.set_values(k, &value_bytes[0..8]).unwrap();},
-                                    _ => { panic!("Unexpected value size for last load event");}
-                                };
-                                // Cache the solving result
-                                if self.solving_results_cache_enable {
-                                    self.solving_results.write().unwrap().insert(last_addr, SolvingResult{target_addr: last_addr, value: v.unwrap(), size: last_size});
+                            },
+                            Ok(SolveOutcome::Solved(solve_result)) => {
+                                self.solve_stats.write().unwrap().solved += 1;
+                                for (k, v) in solve_result {
+                                    log::info!("solving result: ({}, {:?})", k, v);
+                                    let v = match v {
+                                        Some(v) => v,
+                                        None => {
+                                            let size = input_sizes.get(&k).copied().unwrap_or(last_size);
+                                            self.record_solve_event(pc, k, size, None, false);
+                                            continue;
+                                        },
+                                    };
+                                    // Each input has the width of the load that created it
+                                    let size = v.bits() / 8;
+                                    self.record_solve_event(pc, k, size, Some(&v), false);
+                                    // write value to state, in the byte order of the target
+                                    let value_bytes = Self::value_bytes(&v, size, is_little_endian);
+                                    if let Err(e) = state.set_values(k, &value_bytes) {
+                                        log::warn!("Cannot write the solved value of {}: {:?}", k, e);
+                                        continue;
+                                    }
+                                    // Cache the solving result
+                                    if self.solving_results_cache_enable {
+                                        self.solving_results.write().unwrap().insert(k, SolvingResult{target_addr: k, value: v, size});
+                                    }
                                 }
-                            }
+                            },
                         }
                     }
                 }
 
             },
-            PCodeOp::ICall{destination: _} => {
-                self.solving_started = false; 
+            PCodeOp::Call { destination: _ } | PCodeOp::ICall { destination: _ } => {
+                if self.solving_started {
+                    self.call_depth += 1;
+                    if self.call_depth > self.max_call_depth && self.concrete_depth.is_none() {
+                        log::debug!("Call depth {} over the limit, callee executed concretely", self.call_depth);
+                        self.concrete_depth = Some(self.call_depth);
+                    }
+                }
             },
             PCodeOp::Return { destination: _ } => {
-                self.solving_started = false;
+                if self.call_depth == 0 {
+                    // Leaving the function that read the peripheral
+                    self.solving_started = false;
+                } else {
+                    self.call_depth -= 1;
+                    if self.concrete_depth.map_or(false, |depth| self.call_depth < depth) {
+                        self.concrete_depth = None;
+                    }
+                }
             },
             _ => {
 
@@ -310,7 +591,24 @@ where S: State + StateOps,
 
         if self.solving_started {
             // If solving started, add current pcode to the solver to build the tree
-            self.solver.add_pcode(operation.clone(), state.as_ref());
+            let result = if self.concrete_depth.is_some() {
+                self.solver.add_pcode_concrete(operation, state.as_ref())
+            } else {
+                match self.solver.add_pcode(operation, state.as_ref()) {
+                    Err(e) if self.call_depth > 0 => {
+                        // Execute the rest of the callee concretely
+                        log::debug!("Callee at depth {} executed concretely: {}", self.call_depth, e);
+                        self.concrete_depth = Some(self.call_depth);
+                        self.solver.add_pcode_concrete(operation, state.as_ref())
+                    },
+                    result => result,
+                }
+            };
+            if let Err(e) = result {
+                log::warn!("Solving abandoned: {}", e);
+                self.solving_started = false;
+                self.solver.reset();
+            }
         }
 
         self.pcode_counter += 1;
@@ -0,0 +1,327 @@
+//! Flight recorder
+//!
+//! `get_flight_recorder_obs` returns a `TraceHook` keeping only the last N
+//! executed instructions in a ring buffer, optionally with a snapshot of some
+//! registers. `FlightRecorderMemoryHook` adds the memory accesses made by
+//! each instruction to the same buffer. The buffer can be dumped at any time
+//! through the collector, e.g. when a crash or a hang is detected, without
+//! paying for a full trace. Use `FlightRecorder::drain` (through
+//! `collect_mut`) rather than `collect` to empty it: `collect` leaves a
+//! default recorder behind, with the default capacity and a new count.
+use std::collections::VecDeque;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use fugue::ir::{Address, Translator};
+use fugue::bytes::Order;
+use fuguex::concrete::hooks::{ClonableHookConcrete, HookConcrete};
+use fuguex::hooks::types::{HookAction, HookStepAction, HookOutcome, Error};
+use fuguex::state::{
+    AsState,
+    pcode::PCodeState, StateOps};
+use fuguex::machine::StepState;
+
+use crate::observers::trace::{StepFilter, TraceCollector, TraceCollectorError, TraceError, TraceHook};
+use crate::utils::registers::{self, RegisterSet};
+use crate::utils::export::{TraceExportError, TraceWriter};
+
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedAccess {
+    pub kind: AccessKind,
+    pub address: u64,
+    pub size: usize,
+    pub value: Option<Vec<u8>>,     // None if the value could not be read
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlightRecord {
+    pub icount: u64,                        // Index of the instruction since the start of the run
+    pub address: u64,
+    pub registers: Vec<(String, String)>,   // (name, hexadecimal value), before the instruction
+    pub accesses: Vec<RecordedAccess>,
+}
+
+/// Ring buffer of the last executed instructions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlightRecorder {
+    capacity: usize,
+    icount: u64,
+    records: VecDeque<FlightRecord>,
+}
+
+impl Default for FlightRecorder {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl FlightRecorder {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            icount: 0,
+            records: VecDeque::with_capacity(capacity.max(1)),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of instructions seen, including the ones dropped from the buffer
+    pub fn icount(&self) -> u64 {
+        self.icount
+    }
+
+    /// Records, oldest first
+    pub fn records(&self) -> impl Iterator<Item = &FlightRecord> {
+        self.records.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Drop the records, the capacity and instruction count are kept
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    /// Take the records, the capacity and instruction count are kept
+    /// e.g. `collector.collect_mut(FlightRecorder::drain)`
+    pub fn drain(&mut self) -> FlightRecorder {
+        FlightRecorder {
+            capacity: self.capacity,
+            icount: self.icount,
+            records: std::mem::take(&mut self.records),
+        }
+    }
+
+    fn push(&mut self, address: u64, registers: Vec<(String, String)>) {
+        self.icount += 1;
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(FlightRecord {
+            icount: self.icount,
+            address,
+            registers,
+            accesses: Vec::new(),
+        });
+    }
+
+    fn push_access(&mut self, access: RecordedAccess) {
+        if let Some(record) = self.records.back_mut() {
+            record.accesses.push(access);
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(&self.records)
+    }
+
+    /// Write the records as text, one instruction per line, e.g.
+    /// `#1234 pc=0x8000234 r0=0x20000010 mr=0x20000010:efbeadde`
+    pub fn write_text<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+        for record in &self.records {
+            write!(writer, "#{} pc={:#x}", record.icount, record.address)?;
+            for (name, value) in &record.registers {
+                write!(writer, " {}={}", name, value)?;
+            }
+            for access in &record.accesses {
+                let kind = match access.kind {
+                    AccessKind::Read => "mr",
+                    AccessKind::Write => "mw",
+                };
+                write!(writer, " {}={:#x}:", kind, access.address)?;
+                match &access.value {
+                    Some(value) => value.iter().try_for_each(|b| write!(writer, "{:02x}", b))?,
+                    None => write!(writer, "?{}", access.size)?,
+                }
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+}
+
+/// get_flight_recorder_obs()
+/// capacity: number of instructions to keep
+/// registers: registers to snapshot before each instruction, with the translator
+/// used to resolve them, None to only record addresses
+pub fn get_flight_recorder_obs<O: Order>(
+    capacity: usize,
+    registers: Option<(&RegisterSet, &Translator)>)->
+Result<(TraceCollector<FlightRecorder>,
+    TraceHook<PCodeState<u8, O>, O, FlightRecorder>), TraceError> {
+
+    let resolved = match registers {
+        Some((registers, translator)) => registers.resolve(translator)
+            .map_err(TraceError::UnknownRegister)?,
+        None => Vec::new(),
+    };
+
+    Ok(TraceHook::with_events(
+        FlightRecorder::new(capacity),
+        move |address,
+        _pcode,
+        state: & mut PCodeState<u8, O>,
+        collector: & mut FlightRecorder | -> Result<(), TraceCollectorError>{
+            let snapshot = resolved.iter()
+                .filter_map(|(name, register)| {
+                    registers::read_register(state, register)
+                        .map(|value| (name.clone(), registers::value_to_hex::<O>(&value)))
+                })
+                .collect();
+            collector.push(u64::from(address), snapshot);
+            Ok(())
+    }))
+}
+
+/// Memory access observer feeding the buffer of a flight recorder
+/// Accesses are attached to the last recorded instruction, those of the
+/// instructions filtered out by the collector are dropped
+/// S: State
+/// O: Order
+pub struct FlightRecorderMemoryHook<S, O> {
+    recorder: Arc<Mutex<FlightRecorder>>,
+    filter: StepFilter,
+    state: PhantomData<S>,
+    order: PhantomData<O>,
+}
+
+// NOTE: manual implementation avoids adding the trait bounds `S: Clone, O: Clone`.
+impl<S, O> Clone for FlightRecorderMemoryHook<S, O> {
+    fn clone(&self) -> Self {
+        Self {
+            recorder: self.recorder.clone(),
+            filter: self.filter.clone(),
+            state: PhantomData,
+            order: PhantomData,
+        }
+    }
+}
+
+impl<S, O> FlightRecorderMemoryHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    /// collector: collector returned by get_flight_recorder_obs
+    pub fn new(collector: &TraceCollector<FlightRecorder>) -> Self {
+        Self {
+            recorder: collector.shared(),
+            filter: collector.step_filter(),
+            state: PhantomData,
+            order: PhantomData,
+        }
+    }
+}
+
+impl<S: 'static, O> HookConcrete for FlightRecorderMemoryHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    type State = S;
+    type Error = TraceError;
+    type Outcome = String;
+
+    fn hook_architectural_step(&mut self, _state: &mut Self::State, address: &Address, _step_state: &StepState)
+        -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>
+    {
+        // Same filter as the TraceHook, stepping it again on the same address gives the same answer
+        self.filter.step(u64::from(address));
+        Ok(HookStepAction::Pass.into())
+    }
+
+    fn hook_memory_read(
+        &mut self,
+        state: &mut Self::State,
+        address: &Address,
+        size: usize,
+    ) -> Result<HookOutcome<HookAction<Self::Outcome>>, Error<Self::Error>> {
+        if !self.filter.traced() {
+            return Ok(HookAction::Pass.into());
+        }
+        let mut value = vec![0u8; size];
+        let value = state.state_ref().get_values(address, &mut value).ok().map(|_| value);
+        self.recorder.lock().push_access(RecordedAccess {
+            kind: AccessKind::Read,
+            address: u64::from(address),
+            size,
+            value,
+        });
+        Ok(HookAction::Pass.into())
+    }
+
+    fn hook_memory_write(
+        &mut self,
+        _state: &mut Self::State,
+        address: &Address,
+        size: usize,
+        value: &[u8],
+    ) -> Result<HookOutcome<HookAction<Self::Outcome>>, Error<Self::Error>> {
+        if !self.filter.traced() {
+            return Ok(HookAction::Pass.into());
+        }
+        self.recorder.lock().push_access(RecordedAccess {
+            kind: AccessKind::Write,
+            address: u64::from(address),
+            size,
+            value: Some(value.to_vec()),
+        });
+        Ok(HookAction::Pass.into())
+    }
+}
+
+impl<S: 'static, O> ClonableHookConcrete for FlightRecorderMemoryHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+}
+
+/// dump_flight_recorder_to_file()
+/// collector: TraceCollector with FlightRecorder, the buffer is left untouched so
+/// the emulation can go on, e.g. after a hang report
+/// writer: compression and metadata header, JSON has no room for the header
+/// file: records as text, or as JSON if `json` is set
+pub fn dump_flight_recorder_to_file(
+    collector: &TraceCollector<FlightRecorder>,
+    writer: &TraceWriter,
+    file: &str,
+    json: bool) -> Result<usize, TraceExportError> {
+
+    if json {
+        writer.check_headerless("JSON")?;
+    }
+    let written = collector.collect_ref(|recorder| {
+        writer.write_with_path(file, |sink| {
+            if json {
+                let encoded = recorder.to_json()?;
+                sink.write_all(encoded.as_bytes())?;
+            } else {
+                writer.write_text_header(sink)?;
+                recorder.write_text(sink)?;
+            }
+            Ok(recorder.len())
+        })
+    })?;
+    log::info!("Last {} instructions have been dumped to {}", written, file);
+    Ok(written)
+}
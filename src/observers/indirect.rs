@@ -0,0 +1,302 @@
+//! Indirect branch and call targets
+//!
+//! `IndirectTargetHook` records the target of every executed CallInd and
+//! BranchInd operation, per call site. Sites with several targets are
+//! checked for jump tables: the addresses of the table entries loaded just
+//! before the transfer (or, for computed tables, the targets themselves)
+//! must be evenly spaced from a common base.
+//!
+//! Results can be exported as JSON, or as Ghidra and IDA scripts adding the
+//! observed references to a database.
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use fugue::ir::{
+    Address,
+    il::ecode::Location,
+    il::pcode::PCodeOp,
+};
+use fugue::bytes::Order;
+use fuguex::concrete::hooks::{ClonableHookConcrete, HookConcrete};
+use fuguex::hooks::types::{HookStepAction, HookOutcome, Error};
+use fuguex::state::{
+    AsState,
+    pcode::PCodeState, StateOps};
+use fuguex::machine::StepState;
+
+use crate::observers::trace::{StepFilter, TraceCollector, TraceError};
+use crate::utils::registers;
+
+const LOAD_WINDOW: usize = 8;           // Loads remembered to find the table entry of a transfer
+const LOAD_WINDOW_INSTRUCTIONS: u64 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum IndirectKind {
+    Call,
+    Branch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum JumpTableKind {
+    Pointers,   // Entries loaded from memory
+    Computed,   // Targets computed from the index, e.g. `add pc, pc, r0, lsl #2`
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct JumpTable {
+    pub kind: JumpTableKind,
+    pub base: u64,
+    pub stride: u64,
+    pub entries: u64,   // Entries between the lowest and the highest one seen
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndirectSite {
+    pub site: u64,
+    pub kind: Option<IndirectKind>,
+    pub targets: BTreeMap<u64, u64>,    // target: times taken
+    pub table_entries: BTreeSet<u64>,   // Addresses the targets were loaded from
+    pub jump_table: Option<JumpTable>,
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// Common base and stride of evenly spaced addresses, None if there are too few of them
+fn stride_of(addresses: &BTreeSet<u64>, min_entries: usize, strides: &[u64]) -> Option<(u64, u64, u64)> {
+    if addresses.len() < min_entries {
+        return None;
+    }
+    let base = *addresses.iter().next()?;
+    let stride = addresses.iter().fold(0, |acc, address| gcd(acc, address - base));
+    if !strides.contains(&stride) {
+        return None;
+    }
+    let last = *addresses.iter().next_back()?;
+    Some((base, stride, (last - base) / stride + 1))
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndirectTargets {
+    sites: BTreeMap<u64, IndirectSite>,
+    #[serde(skip)]
+    recent_loads: VecDeque<(u64, u64, u64)>,    // (instruction count, address, loaded value)
+}
+
+impl IndirectTargets {
+    pub fn sites(&self) -> impl Iterator<Item = &IndirectSite> {
+        self.sites.values()
+    }
+
+    pub fn site(&self, site: u64) -> Option<&IndirectSite> {
+        self.sites.get(&site)
+    }
+
+    fn record_load(&mut self, icount: u64, address: u64, value: u64) {
+        if self.recent_loads.len() == LOAD_WINDOW {
+            self.recent_loads.pop_front();
+        }
+        self.recent_loads.push_back((icount, address, value));
+    }
+
+    fn record_transfer(&mut self, icount: u64, site: u64, kind: IndirectKind, target: u64) {
+        // The table entry is a recent load of the target itself (ignoring the Thumb bit),
+        // or the latest recent load for tables of offsets
+        let recent = self.recent_loads.iter().rev()
+            .filter(|(count, _, _)| icount - count <= LOAD_WINDOW_INSTRUCTIONS);
+        let entry = recent.clone().find(|(_, _, value)| *value & !1 == target & !1)
+            .or_else(|| recent.clone().next())
+            .map(|(_, address, _)| *address);
+        self.recent_loads.clear();
+
+        let record = self.sites.entry(site).or_insert_with(|| IndirectSite {
+            site,
+            ..Default::default()
+        });
+        record.kind = Some(kind);
+        *record.targets.entry(target).or_insert(0) += 1;
+        if let Some(entry) = entry {
+            record.table_entries.insert(entry);
+        }
+    }
+
+    /// Look for jump tables among the sites with at least `min_targets` targets
+    pub fn detect_jump_tables(&mut self, min_targets: usize) {
+        for site in self.sites.values_mut() {
+            site.jump_table = None;
+            if site.targets.len() < min_targets {
+                continue;
+            }
+            if let Some((base, stride, entries)) = stride_of(&site.table_entries, min_targets, &[1, 2, 4, 8]) {
+                site.jump_table = Some(JumpTable { kind: JumpTableKind::Pointers, base, stride, entries });
+                continue;
+            }
+            let targets: BTreeSet<u64> = site.targets.keys().cloned().collect();
+            if let Some((base, stride, entries)) = stride_of(&targets, min_targets, &[2, 4, 8, 16]) {
+                site.jump_table = Some(JumpTable { kind: JumpTableKind::Computed, base, stride, entries });
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(&self.sites.values().collect::<Vec<_>>())
+    }
+
+    fn references(&self, clear_thumb_bit: bool) -> Vec<(u64, u64, bool)> {
+        let mask = if clear_thumb_bit { !1u64 } else { !0u64 };
+        self.sites.values()
+            .flat_map(|site| {
+                let is_call = site.kind == Some(IndirectKind::Call);
+                site.targets.keys().map(move |target| (site.site, target & mask, is_call))
+            })
+            .collect()
+    }
+
+    fn write_reference_list<W: Write + ?Sized>(writer: &mut W, references: &[(u64, u64, bool)]) -> std::io::Result<()> {
+        writeln!(writer, "references = [")?;
+        for (site, target, is_call) in references {
+            writeln!(writer, "    ({:#x}, {:#x}, {}),", site, target, if *is_call { "True" } else { "False" })?;
+        }
+        writeln!(writer, "]")
+    }
+
+    /// Write a Ghidra (Jython) script adding the observed references
+    /// clear_thumb_bit: clear bit 0 of the targets, for ARM Thumb code
+    pub fn write_ghidra_script<W: Write + ?Sized>(&self, writer: &mut W, clear_thumb_bit: bool) -> std::io::Result<()> {
+        writeln!(writer, "# Indirect call/branch targets observed during emulation")?;
+        writeln!(writer, "from ghidra.program.model.symbol import RefType, SourceType")?;
+        writeln!(writer)?;
+        Self::write_reference_list(writer, &self.references(clear_thumb_bit))?;
+        writeln!(writer)?;
+        writeln!(writer, "manager = currentProgram.getReferenceManager()")?;
+        writeln!(writer, "for site, target, is_call in references:")?;
+        writeln!(writer, "    ref_type = RefType.COMPUTED_CALL if is_call else RefType.COMPUTED_JUMP")?;
+        writeln!(writer, "    manager.addMemoryReference(toAddr(site), toAddr(target), ref_type, SourceType.USER_DEFINED, 0)")?;
+        writeln!(writer, "print(\"%d references added\" % len(references))")?;
+        writer.flush()
+    }
+
+    /// Write an IDAPython script adding the observed references
+    /// clear_thumb_bit: clear bit 0 of the targets, for ARM Thumb code
+    pub fn write_ida_script<W: Write + ?Sized>(&self, writer: &mut W, clear_thumb_bit: bool) -> std::io::Result<()> {
+        writeln!(writer, "# Indirect call/branch targets observed during emulation")?;
+        writeln!(writer, "import ida_xref")?;
+        writeln!(writer)?;
+        Self::write_reference_list(writer, &self.references(clear_thumb_bit))?;
+        writeln!(writer)?;
+        writeln!(writer, "for site, target, is_call in references:")?;
+        writeln!(writer, "    flow = ida_xref.fl_CN if is_call else ida_xref.fl_JN")?;
+        writeln!(writer, "    ida_xref.add_cref(site, target, flow | ida_xref.XREF_USER)")?;
+        writeln!(writer, "print(\"%d references added\" % len(references))")?;
+        writer.flush()
+    }
+}
+
+/// Indirect target observer
+/// S: State
+/// O: Order
+pub struct IndirectTargetHook<S, O> {
+    icount: u64,
+    pc: u64,
+    targets: Arc<Mutex<IndirectTargets>>,
+    filter: StepFilter,
+    state: PhantomData<S>,
+    order: PhantomData<O>,
+}
+
+// NOTE: manual implementation avoids adding the trait bounds `S: Clone, O: Clone`.
+impl<S, O> Clone for IndirectTargetHook<S, O> {
+    fn clone(&self) -> Self {
+        Self {
+            icount: self.icount,
+            pc: self.pc,
+            targets: self.targets.clone(),
+            filter: self.filter.clone(),
+            state: PhantomData,
+            order: PhantomData,
+        }
+    }
+}
+
+impl<S, O> IndirectTargetHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    pub fn new() -> (TraceCollector<IndirectTargets>, Self) {
+        let targets = Arc::new(Mutex::new(IndirectTargets::default()));
+        let collector = TraceCollector::from_shared(targets.clone());
+
+        let observer = Self {
+            icount: 0,
+            pc: 0,
+            targets,
+            filter: collector.step_filter(),
+            state: PhantomData,
+            order: PhantomData,
+        };
+
+        (collector, observer)
+    }
+}
+
+impl<S: 'static, O> HookConcrete for IndirectTargetHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    type State = S;
+    type Error = TraceError;
+    type Outcome = String;
+
+    fn hook_architectural_step(&mut self, _state: &mut Self::State, address: &Address, _step_state: &StepState)
+        -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>
+    {
+        self.icount += 1;
+        self.pc = u64::from(address);
+        self.filter.step(self.pc);
+        Ok(HookStepAction::Pass.into())
+    }
+
+    fn hook_operation_step(
+        &mut self,
+        state: &mut Self::State,
+        _location: &Location,
+        operation: &PCodeOp,
+    ) -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>> {
+        let state = state.state_ref();
+        match operation {
+            PCodeOp::Load { source, destination, space: _ } => {
+                // Remember where values are loaded from, they may be jump table entries
+                // Loads are kept whatever the filter: the entry of a traced transfer
+                // may be loaded by an instruction filtered out
+                if let Ok(address) = state.get_address(source) {
+                    let mut value = vec![0u8; destination.size()];
+                    if state.get_values(&address, &mut value).is_ok() {
+                        self.targets.lock().record_load(self.icount, u64::from(address), registers::value_to_u64::<O>(&value));
+                    }
+                }
+            },
+            PCodeOp::ICall { destination } | PCodeOp::IBranch { destination } if self.filter.traced() => {
+                let kind = if matches!(operation, PCodeOp::ICall { .. }) { IndirectKind::Call } else { IndirectKind::Branch };
+                match state.with_operand_values(destination, |values| registers::value_to_u64::<O>(values)) {
+                    Ok(target) => self.targets.lock().record_transfer(self.icount, self.pc, kind, target),
+                    Err(_) => log::warn!("PC {:#x}: cannot read the target of {:?}", self.pc, operation),
+                }
+            },
+            _ => (),
+        }
+        Ok(HookStepAction::Pass.into())
+    }
+}
+
+impl<S: 'static, O> ClonableHookConcrete for IndirectTargetHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+}
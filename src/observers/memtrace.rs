@@ -0,0 +1,173 @@
+//! Memory access trace
+//!
+//! `MemoryTraceHook` records every data access seen by `hook_memory_read` and
+//! `hook_memory_write` as a `memtrace::MemAccess`, tagged with the index and
+//! PC of the instruction that made it. Address ranges can be used to only
+//! keep accesses to e.g. RAM or MMIO, and the filter of the collector to only
+//! keep the accesses of some instructions.
+use std::fs::File;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::io::BufWriter;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use fugue::ir::Address;
+use fugue::bytes::Order;
+use fuguex::concrete::hooks::{ClonableHookConcrete, HookConcrete};
+use fuguex::hooks::types::{HookAction, HookStepAction, HookOutcome, Error};
+use fuguex::state::{
+    AsState,
+    pcode::PCodeState, StateOps};
+use fuguex::machine::StepState;
+
+use crate::observers::trace::{StepFilter, TraceCollector, TraceError};
+use crate::utils::memtrace::{MemAccess, MemAccessKind, MemAccesses};
+use crate::utils::export::{TraceExportError, TraceWriter};
+
+/// Memory access observer
+/// S: State
+/// O: Order
+pub struct MemoryTraceHook<S, O> {
+    address_range_list: Vec<(u64, u64)>,    // Inclusive ranges to record, record everything if empty
+    icount: u64,                            // Index of the current instruction
+    pc: u64,
+    accesses: Arc<Mutex<MemAccesses>>,
+    filter: StepFilter,
+    state: PhantomData<S>,
+    order: PhantomData<O>,
+}
+
+// NOTE: manual implementation avoids adding the trait bounds `S: Clone, O: Clone`.
+impl<S, O> Clone for MemoryTraceHook<S, O> {
+    fn clone(&self) -> Self {
+        Self {
+            address_range_list: self.address_range_list.clone(),
+            icount: self.icount,
+            pc: self.pc,
+            accesses: self.accesses.clone(),
+            filter: self.filter.clone(),
+            state: PhantomData,
+            order: PhantomData,
+        }
+    }
+}
+
+impl<S, O> MemoryTraceHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    pub fn new() -> (TraceCollector<MemAccesses>, Self) {
+        let accesses = Arc::new(Mutex::new(MemAccesses::new()));
+        let collector = TraceCollector::from_shared(accesses.clone());
+
+        let observer = Self {
+            address_range_list: Vec::new(),
+            icount: 0,
+            pc: 0,
+            accesses,
+            filter: collector.step_filter(),
+            state: PhantomData,
+            order: PhantomData,
+        };
+
+        (collector, observer)
+    }
+
+    /// Only record accesses inside the range, both ends included
+    /// Can be called several times, e.g. once for RAM and once for MMIO
+    pub fn add_address_range<A>(&mut self, addr_range: (A, A)) where A: Into<Address> {
+        let (addr_start, addr_end) = addr_range;
+        self.address_range_list.push((u64::from(addr_start.into()), u64::from(addr_end.into())));
+    }
+
+    fn is_traced(&self, address: u64) -> bool {
+        self.filter.traced() && (self.address_range_list.is_empty()
+            || self.address_range_list.iter().any(|(min, max)| *min <= address && address <= *max))
+    }
+
+    fn record(&self, address: u64, size: usize, value: Option<&[u8]>, kind: MemAccessKind) {
+        let mut access = MemAccess::new();
+        access.set_icount(self.icount);
+        access.set_pc(self.pc);
+        access.set_address(address);
+        access.set_size(size as u32);
+        if let Some(value) = value {
+            access.set_value(value.to_vec());
+        }
+        access.set_kind(kind);
+        self.accesses.lock().accesses.push(access);
+    }
+}
+
+impl<S: 'static, O> HookConcrete for MemoryTraceHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    type State = S;
+    type Error = TraceError;
+    type Outcome = String;
+
+    fn hook_architectural_step(&mut self, _state: &mut Self::State, address: &Address, _step_state: &StepState)
+        -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>
+    {
+        self.icount += 1;
+        self.pc = u64::from(address);
+        self.filter.step(self.pc);
+        Ok(HookStepAction::Pass.into())
+    }
+
+    fn hook_memory_read(
+        &mut self,
+        state: &mut Self::State,
+        address: &Address,
+        size: usize,
+    ) -> Result<HookOutcome<HookAction<Self::Outcome>>, Error<Self::Error>> {
+        let offset = u64::from(address);
+        if self.is_traced(offset) {
+            // Value is left out if it cannot be read, e.g. for unmapped memory
+            let mut value = vec![0u8; size];
+            let value = state.state_ref().get_values(address, &mut value).ok().map(|_| value);
+            self.record(offset, size, value.as_deref(), MemAccessKind::READ);
+        }
+        Ok(HookAction::Pass.into())
+    }
+
+    fn hook_memory_write(
+        &mut self,
+        _state: &mut Self::State,
+        address: &Address,
+        size: usize,
+        value: &[u8],
+    ) -> Result<HookOutcome<HookAction<Self::Outcome>>, Error<Self::Error>> {
+        let offset = u64::from(address);
+        if self.is_traced(offset) {
+            self.record(offset, size, Some(value), MemAccessKind::WRITE);
+        }
+        Ok(HookAction::Pass.into())
+    }
+}
+
+impl<S: 'static, O> ClonableHookConcrete for MemoryTraceHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+}
+
+/// collect_memory_trace_to_file()
+/// collector: TraceCollector with MemAccesses
+/// writer: compression and metadata header
+/// file: trace in memtrace protobuf format
+pub fn collect_memory_trace_to_file(
+    mut collector: TraceCollector<MemAccesses>,
+    writer: &TraceWriter,
+    file: &str) -> Result<usize, TraceExportError> {
+
+    let collect_res = collector.collect();
+    let file = File::create(Path::new(file))?;
+    writer.write_protobuf(BufWriter::new(file), &collect_res)?;
+    log::info!("{} memory accesses has been logged", collect_res.accesses.len());
+    Ok(collect_res.accesses.len())
+}
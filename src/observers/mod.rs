@@ -9,4 +9,15 @@ pub use trace::*;
 
 pub mod watchpoint;
 pub mod dummy_peripheral;
-mod solver;
\ No newline at end of file
+pub mod solver;
+pub mod coverage;
+pub mod memtrace;
+pub mod tenet;
+pub mod pcode_trace;
+pub mod callstack;
+pub mod profiler;
+pub mod cfg;
+pub mod indirect;
+pub mod flight_recorder;
+pub mod timeline;
+pub mod vcd;
@@ -0,0 +1,528 @@
+//! P-code operation trace
+//!
+//! `PCodeTraceHook` records every executed `PCodeOp` together with the
+//! concrete values of its inputs and of its output. The resulting
+//! `PCodeTrace` is serializable, and `PCodeTrace::replay` re-evaluates the
+//! recorded operations offline to find operations whose output does not
+//! match their semantics, i.e. lifter or emulator discrepancies.
+//!
+//! The output of an operation is read once it has been executed, on the next
+//! step: the last operation recorded before the trace is drained (e.g. the
+//! last one of the run) has no output, and is skipped by the replay.
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use fugue::ir::{
+    Address,
+    il::ecode::Location,
+    il::pcode::{Operand, PCodeOp},
+};
+use fugue::bytes::Order;
+use fuguex::concrete::hooks::{ClonableHookConcrete, HookConcrete};
+use fuguex::hooks::types::{HookStepAction, HookOutcome, Error};
+use fuguex::state::{
+    AsState,
+    pcode::PCodeState, StateOps};
+use fuguex::machine::StepState;
+
+use crate::observers::trace::{StepFilter, TraceCollector, TraceError};
+use crate::utils::export::{TraceExportError, TraceWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PCodeOpKind {
+    Copy, Load, Store,
+    Branch, CBranch, IBranch, Call, ICall, Return,
+    IntEq, IntNotEq, IntLess, IntSLess, IntLessEq, IntSLessEq,
+    IntZExt, IntSExt,
+    IntAdd, IntSub, IntCarry, IntSCarry, IntSBorrow, IntNeg, IntNot,
+    IntXor, IntAnd, IntOr, IntLeftShift, IntRightShift, IntSRightShift,
+    IntMul, IntDiv, IntSDiv, IntRem, IntSRem,
+    BoolNot, BoolXor, BoolAnd, BoolOr,
+    Subpiece, PopCount, Skip,
+    Other,      // Operations without replay support, e.g. intrinsics and floating point
+}
+
+impl PCodeOpKind {
+    pub fn is_branch(&self) -> bool {
+        matches!(self,
+            PCodeOpKind::Branch | PCodeOpKind::CBranch | PCodeOpKind::IBranch
+            | PCodeOpKind::Call | PCodeOpKind::ICall | PCodeOpKind::Return)
+    }
+}
+
+/// Split an operation into its kind, input operands and output operand
+pub(crate) fn decompose(operation: &PCodeOp) -> (PCodeOpKind, Vec<&Operand>, Option<&Operand>) {
+    let kind = match operation {
+        PCodeOp::Copy { .. } => PCodeOpKind::Copy,
+        PCodeOp::Load { .. } => PCodeOpKind::Load,
+        PCodeOp::Store { .. } => PCodeOpKind::Store,
+        PCodeOp::Branch { .. } => PCodeOpKind::Branch,
+        PCodeOp::CBranch { .. } => PCodeOpKind::CBranch,
+        PCodeOp::IBranch { .. } => PCodeOpKind::IBranch,
+        PCodeOp::Call { .. } => PCodeOpKind::Call,
+        PCodeOp::ICall { .. } => PCodeOpKind::ICall,
+        PCodeOp::Return { .. } => PCodeOpKind::Return,
+        PCodeOp::IntEq { .. } => PCodeOpKind::IntEq,
+        PCodeOp::IntNotEq { .. } => PCodeOpKind::IntNotEq,
+        PCodeOp::IntLess { .. } => PCodeOpKind::IntLess,
+        PCodeOp::IntSLess { .. } => PCodeOpKind::IntSLess,
+        PCodeOp::IntLessEq { .. } => PCodeOpKind::IntLessEq,
+        PCodeOp::IntSLessEq { .. } => PCodeOpKind::IntSLessEq,
+        PCodeOp::IntZExt { .. } => PCodeOpKind::IntZExt,
+        PCodeOp::IntSExt { .. } => PCodeOpKind::IntSExt,
+        PCodeOp::IntAdd { .. } => PCodeOpKind::IntAdd,
+        PCodeOp::IntSub { .. } => PCodeOpKind::IntSub,
+        PCodeOp::IntCarry { .. } => PCodeOpKind::IntCarry,
+        PCodeOp::IntSCarry { .. } => PCodeOpKind::IntSCarry,
+        PCodeOp::IntSBorrow { .. } => PCodeOpKind::IntSBorrow,
+        PCodeOp::IntNeg { .. } => PCodeOpKind::IntNeg,
+        PCodeOp::IntNot { .. } => PCodeOpKind::IntNot,
+        PCodeOp::IntXor { .. } => PCodeOpKind::IntXor,
+        PCodeOp::IntAnd { .. } => PCodeOpKind::IntAnd,
+        PCodeOp::IntOr { .. } => PCodeOpKind::IntOr,
+        PCodeOp::IntLeftShift { .. } => PCodeOpKind::IntLeftShift,
+        PCodeOp::IntRightShift { .. } => PCodeOpKind::IntRightShift,
+        PCodeOp::IntSRightShift { .. } => PCodeOpKind::IntSRightShift,
+        PCodeOp::IntMul { .. } => PCodeOpKind::IntMul,
+        PCodeOp::IntDiv { .. } => PCodeOpKind::IntDiv,
+        PCodeOp::IntSDiv { .. } => PCodeOpKind::IntSDiv,
+        PCodeOp::IntRem { .. } => PCodeOpKind::IntRem,
+        PCodeOp::IntSRem { .. } => PCodeOpKind::IntSRem,
+        PCodeOp::BoolNot { .. } => PCodeOpKind::BoolNot,
+        PCodeOp::BoolXor { .. } => PCodeOpKind::BoolXor,
+        PCodeOp::BoolAnd { .. } => PCodeOpKind::BoolAnd,
+        PCodeOp::BoolOr { .. } => PCodeOpKind::BoolOr,
+        PCodeOp::Subpiece { .. } => PCodeOpKind::Subpiece,
+        PCodeOp::PopCount { .. } => PCodeOpKind::PopCount,
+        PCodeOp::Skip => PCodeOpKind::Skip,
+        _ => PCodeOpKind::Other,
+    };
+
+    match operation {
+        PCodeOp::Copy { source, destination } => (kind, vec![source], Some(destination)),
+        PCodeOp::Load { source, destination, space: _ } => (kind, vec![source], Some(destination)),
+        PCodeOp::Store { source, destination, space: _ } => (kind, vec![destination, source], None),
+        PCodeOp::Branch { destination }
+        | PCodeOp::IBranch { destination }
+        | PCodeOp::Call { destination }
+        | PCodeOp::ICall { destination }
+        | PCodeOp::Return { destination } => (kind, vec![destination], None),
+        PCodeOp::CBranch { destination, condition } => (kind, vec![destination, condition], None),
+        PCodeOp::IntEq { result, operands }
+        | PCodeOp::IntNotEq { result, operands }
+        | PCodeOp::IntLess { result, operands }
+        | PCodeOp::IntSLess { result, operands }
+        | PCodeOp::IntLessEq { result, operands }
+        | PCodeOp::IntSLessEq { result, operands }
+        | PCodeOp::IntAdd { result, operands }
+        | PCodeOp::IntSub { result, operands }
+        | PCodeOp::IntCarry { result, operands }
+        | PCodeOp::IntSCarry { result, operands }
+        | PCodeOp::IntSBorrow { result, operands }
+        | PCodeOp::IntXor { result, operands }
+        | PCodeOp::IntAnd { result, operands }
+        | PCodeOp::IntOr { result, operands }
+        | PCodeOp::IntLeftShift { result, operands }
+        | PCodeOp::IntRightShift { result, operands }
+        | PCodeOp::IntSRightShift { result, operands }
+        | PCodeOp::IntMul { result, operands }
+        | PCodeOp::IntDiv { result, operands }
+        | PCodeOp::IntSDiv { result, operands }
+        | PCodeOp::IntRem { result, operands }
+        | PCodeOp::IntSRem { result, operands }
+        | PCodeOp::BoolXor { result, operands }
+        | PCodeOp::BoolAnd { result, operands }
+        | PCodeOp::BoolOr { result, operands } => (kind, vec![&operands[0], &operands[1]], Some(result)),
+        PCodeOp::IntZExt { result, operand }
+        | PCodeOp::IntSExt { result, operand }
+        | PCodeOp::IntNeg { result, operand }
+        | PCodeOp::IntNot { result, operand }
+        | PCodeOp::BoolNot { result, operand }
+        | PCodeOp::PopCount { result, operand } => (kind, vec![operand], Some(result)),
+        PCodeOp::Subpiece { result, operand, amount } => (kind, vec![operand, amount], Some(result)),
+        _ => (kind, Vec::new(), None),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OperandKind {
+    Constant,
+    Address,
+    Register,
+    Variable,
+}
+
+/// An operand and its concrete value when the operation was executed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperandValue {
+    pub name: String,
+    pub kind: OperandKind,
+    pub offset: u64,
+    pub size: usize,
+    pub value: Option<Vec<u8>>,     // In the byte order of the trace, None if it could not be read
+}
+
+impl OperandValue {
+    fn read<O: Order>(state: &PCodeState<u8, O>, operand: &Operand) -> Self {
+        let (kind, offset, value) = match operand {
+            Operand::Constant { value, size } => {
+                let bytes = value.to_le_bytes();
+                let mut bytes = bytes[..(*size).min(8)].to_vec();
+                bytes.resize(*size, 0);
+                if !O::ENDIAN.is_little() {
+                    bytes.reverse();
+                }
+                (OperandKind::Constant, *value, Some(bytes))
+            },
+            Operand::Address { value, size: _ } => {
+                (OperandKind::Address, value.offset(), state.with_operand_values(operand, |v| v.to_vec()).ok())
+            },
+            Operand::Register { name: _, offset, size: _ } => {
+                (OperandKind::Register, *offset, state.with_operand_values(operand, |v| v.to_vec()).ok())
+            },
+            Operand::Variable { space: _, offset, size: _ } => {
+                (OperandKind::Variable, *offset, state.with_operand_values(operand, |v| v.to_vec()).ok())
+            },
+        };
+
+        Self {
+            name: format!("{}", operand),
+            kind,
+            offset,
+            size: operand.size(),
+            value,
+        }
+    }
+}
+
+/// An executed operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PCodeRecord {
+    pub icount: u64,        // Index of the instruction the operation belongs to
+    pub pc: u64,
+    pub position: usize,    // Index of the operation inside the instruction
+    pub kind: PCodeOpKind,
+    pub text: String,
+    pub inputs: Vec<OperandValue>,
+    pub output: Option<OperandValue>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PCodeTrace {
+    pub little_endian: bool,
+    pub records: Vec<PCodeRecord>,
+    #[serde(skip)]
+    drained: usize,     // Records taken by `drain` before the first one
+}
+
+/// An operation whose recorded output differs from the replayed one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayMismatch {
+    pub record: usize,      // Index in PCodeTrace::records
+    pub pc: u64,
+    pub text: String,
+    pub recorded: u128,
+    pub replayed: u128,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReplayReport {
+    pub checked: usize,
+    pub skipped: usize,     // Unsupported operations, missing values or values wider than 128 bits
+    pub mismatches: Vec<ReplayMismatch>,
+}
+
+fn mask(bits: usize) -> u128 {
+    if bits >= 128 { u128::MAX } else { (1u128 << bits) - 1 }
+}
+
+fn sign_extend(value: u128, bits: usize) -> i128 {
+    if bits == 0 || bits >= 128 {
+        return value as i128;
+    }
+    let shift = 128 - bits;
+    ((value << shift) as i128) >> shift
+}
+
+impl PCodeTrace {
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn write_json<W: Write>(&self, writer: W) -> serde_json::Result<()> {
+        serde_json::to_writer(writer, self)
+    }
+
+    pub fn read_json<R: Read>(reader: R) -> serde_json::Result<Self> {
+        serde_json::from_reader(reader)
+    }
+
+    /// Take the records, keeping the byte order for the next ones
+    pub fn drain(&mut self) -> PCodeTrace {
+        let records = std::mem::take(&mut self.records);
+        let drained = self.drained;
+        self.drained += records.len();
+        PCodeTrace {
+            little_endian: self.little_endian,
+            records,
+            drained,
+        }
+    }
+
+    fn to_u128(&self, value: &OperandValue) -> Option<u128> {
+        let bytes = value.value.as_ref()?;
+        if bytes.len() > 16 {
+            return None;
+        }
+        let ordered: Vec<u8> = if self.little_endian {
+            bytes.iter().rev().cloned().collect()
+        } else {
+            bytes.clone()
+        };
+        Some(ordered.iter().fold(0u128, |acc, b| (acc << 8) | *b as u128))
+    }
+
+    /// Evaluate a record from its recorded inputs
+    fn evaluate(&self, record: &PCodeRecord) -> Option<u128> {
+        let output = record.output.as_ref()?;
+        let out_bits = output.size * 8;
+        let inputs = record.inputs.iter()
+            .map(|input| self.to_u128(input))
+            .collect::<Option<Vec<_>>>()?;
+        let in_bits = record.inputs.first()?.size * 8;
+        let a = *inputs.first()?;
+        let b = inputs.get(1).cloned().unwrap_or(0);
+        let m = mask(in_bits);
+
+        let value = match record.kind {
+            PCodeOpKind::Copy => a,
+            PCodeOpKind::IntAdd => a.wrapping_add(b),
+            PCodeOpKind::IntSub => a.wrapping_sub(b),
+            PCodeOpKind::IntMul => a.wrapping_mul(b),
+            PCodeOpKind::IntDiv => a.checked_div(b)?,
+            PCodeOpKind::IntRem => a.checked_rem(b)?,
+            PCodeOpKind::IntSDiv => sign_extend(a, in_bits).checked_div(sign_extend(b, in_bits))? as u128,
+            PCodeOpKind::IntSRem => sign_extend(a, in_bits).checked_rem(sign_extend(b, in_bits))? as u128,
+            PCodeOpKind::IntNeg => a.wrapping_neg(),
+            PCodeOpKind::IntNot => !a,
+            PCodeOpKind::IntAnd => a & b,
+            PCodeOpKind::IntOr => a | b,
+            PCodeOpKind::IntXor => a ^ b,
+            PCodeOpKind::IntLeftShift => if b >= in_bits as u128 { 0 } else { a << b },
+            PCodeOpKind::IntRightShift => if b >= in_bits as u128 { 0 } else { a >> b },
+            PCodeOpKind::IntSRightShift => {
+                let shift = b.min(in_bits as u128 - 1) as u32;
+                (sign_extend(a, in_bits) >> shift) as u128
+            },
+            PCodeOpKind::IntEq => (a == b) as u128,
+            PCodeOpKind::IntNotEq => (a != b) as u128,
+            PCodeOpKind::IntLess => (a < b) as u128,
+            PCodeOpKind::IntLessEq => (a <= b) as u128,
+            PCodeOpKind::IntSLess => (sign_extend(a, in_bits) < sign_extend(b, in_bits)) as u128,
+            PCodeOpKind::IntSLessEq => (sign_extend(a, in_bits) <= sign_extend(b, in_bits)) as u128,
+            PCodeOpKind::IntZExt => a,
+            PCodeOpKind::IntSExt => sign_extend(a, in_bits) as u128,
+            PCodeOpKind::IntCarry => (a.wrapping_add(b) & m < a) as u128,
+            PCodeOpKind::IntSCarry => {
+                let sum = sign_extend(a, in_bits).wrapping_add(sign_extend(b, in_bits));
+                (sign_extend(sum as u128 & m, in_bits) != sum) as u128
+            },
+            PCodeOpKind::IntSBorrow => {
+                let diff = sign_extend(a, in_bits).wrapping_sub(sign_extend(b, in_bits));
+                (sign_extend(diff as u128 & m, in_bits) != diff) as u128
+            },
+            PCodeOpKind::BoolNot => (a == 0) as u128,
+            PCodeOpKind::BoolAnd => (a != 0 && b != 0) as u128,
+            PCodeOpKind::BoolOr => (a != 0 || b != 0) as u128,
+            PCodeOpKind::BoolXor => ((a != 0) ^ (b != 0)) as u128,
+            PCodeOpKind::Subpiece => if b >= 16 { 0 } else { a >> (b * 8) },
+            PCodeOpKind::PopCount => a.count_ones() as u128,
+            _ => return None,
+        };
+
+        Some(value & mask(out_bits))
+    }
+
+    /// Re-evaluate every operation from its recorded inputs and compare with its recorded output
+    pub fn replay(&self) -> ReplayReport {
+        let mut report = ReplayReport::default();
+        for (index, record) in self.records.iter().enumerate() {
+            let recorded = record.output.as_ref().and_then(|output| self.to_u128(output));
+            match (recorded, self.evaluate(record)) {
+                (Some(recorded), Some(replayed)) => {
+                    report.checked += 1;
+                    if recorded != replayed {
+                        report.mismatches.push(ReplayMismatch {
+                            record: index,
+                            pc: record.pc,
+                            text: record.text.clone(),
+                            recorded,
+                            replayed,
+                        });
+                    }
+                },
+                _ => {
+                    report.skipped += 1;
+                }
+            }
+        }
+        report
+    }
+}
+
+/// P-code trace observer
+/// S: State
+/// O: Order
+pub struct PCodeTraceHook<S, O> {
+    icount: u64,
+    pc: u64,
+    position: usize,
+    pending_output: Option<(usize, Operand)>,   // Output read once the operation has been executed, index counting the drained records
+    trace: Arc<Mutex<PCodeTrace>>,
+    filter: StepFilter,
+    state: PhantomData<S>,
+    order: PhantomData<O>,
+}
+
+// NOTE: manual implementation avoids adding the trait bounds `S: Clone, O: Clone`.
+impl<S, O> Clone for PCodeTraceHook<S, O> {
+    fn clone(&self) -> Self {
+        Self {
+            icount: self.icount,
+            pc: self.pc,
+            position: self.position,
+            pending_output: self.pending_output.clone(),
+            trace: self.trace.clone(),
+            filter: self.filter.clone(),
+            state: PhantomData,
+            order: PhantomData,
+        }
+    }
+}
+
+impl<S, O> PCodeTraceHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    pub fn new() -> (TraceCollector<PCodeTrace>, Self) {
+        let trace = Arc::new(Mutex::new(PCodeTrace {
+            little_endian: O::ENDIAN.is_little(),
+            records: Vec::new(),
+            drained: 0,
+        }));
+        let collector = TraceCollector::from_shared(trace.clone());
+
+        let observer = Self {
+            icount: 0,
+            pc: 0,
+            position: 0,
+            pending_output: None,
+            trace,
+            filter: collector.step_filter(),
+            state: PhantomData,
+            order: PhantomData,
+        };
+
+        (collector, observer)
+    }
+
+    // Record the output of the previous operation, which has been executed by now
+    // The output is dropped if its record has been drained meanwhile
+    fn complete_pending(&mut self, state: &PCodeState<u8, O>) {
+        if let Some((index, operand)) = self.pending_output.take() {
+            let mut trace = self.trace.lock();
+            let index = match index.checked_sub(trace.drained) {
+                Some(index) => index,
+                None => return,
+            };
+            if let Some(record) = trace.records.get_mut(index) {
+                record.output = Some(OperandValue::read(state, &operand));
+            }
+        }
+    }
+}
+
+impl<S: 'static, O> HookConcrete for PCodeTraceHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    type State = S;
+    type Error = TraceError;
+    type Outcome = String;
+
+    fn hook_architectural_step(&mut self, state: &mut Self::State, address: &Address, _step_state: &StepState)
+        -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>
+    {
+        self.complete_pending(state.state_ref());
+        self.icount += 1;
+        self.pc = u64::from(address);
+        self.position = 0;
+        self.filter.step(self.pc);
+        Ok(HookStepAction::Pass.into())
+    }
+
+    fn hook_operation_step(
+        &mut self,
+        state: &mut Self::State,
+        _location: &Location,
+        operation: &PCodeOp,
+    ) -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>> {
+        let state = state.state_ref();
+        self.complete_pending(state);
+        if !self.filter.traced() {
+            return Ok(HookStepAction::Pass.into());
+        }
+
+        let (kind, inputs, output) = decompose(operation);
+        let record = PCodeRecord {
+            icount: self.icount,
+            pc: self.pc,
+            position: self.position,
+            kind,
+            text: format!("{}", operation),
+            inputs: inputs.into_iter().map(|input| OperandValue::read(state, input)).collect(),
+            output: None,
+        };
+
+        let mut trace = self.trace.lock();
+        trace.records.push(record);
+        self.pending_output = output.map(|output| (trace.drained + trace.records.len() - 1, output.clone()));
+        self.position += 1;
+
+        Ok(HookStepAction::Pass.into())
+    }
+}
+
+impl<S: 'static, O> ClonableHookConcrete for PCodeTraceHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+}
+
+/// collect_pcode_trace_to_file()
+/// collector: TraceCollector with PCodeTrace
+/// writer: compression, JSON has no room for a metadata header
+/// file: trace in JSON, can be loaded back with PCodeTrace::read_json()
+pub fn collect_pcode_trace_to_file(
+    mut collector: TraceCollector<PCodeTrace>,
+    writer: &TraceWriter,
+    file: &str) -> Result<usize, TraceExportError> {
+
+    writer.check_headerless("JSON")?;
+    let collect_res = collector.collect_mut(PCodeTrace::drain);
+    let written = writer.write_with_path(file, |sink| {
+        collect_res.write_json(sink)?;
+        Ok(collect_res.len())
+    })?;
+    log::info!("{} P-code operations has been logged", written);
+    Ok(written)
+}
@@ -0,0 +1,343 @@
+//! Per-function instruction profiler
+//!
+//! `ProfilerHook` attributes executed instructions and P-code operations to
+//! functions. Function boundaries come from a user-supplied symbol table
+//! when there is one, otherwise from the call targets discovered by the
+//! shadow call stack. The report holds inclusive and exclusive counts, call
+//! counts and the hottest loops (taken backward branches inside a function).
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use fugue::ir::Address;
+use fugue::bytes::Order;
+use fuguex::concrete::hooks::{ClonableHookConcrete, HookConcrete};
+use fuguex::hooks::types::{HookStepAction, HookOutcome, Error};
+use fuguex::state::{
+    AsState,
+    pcode::PCodeState, StateOps};
+use fuguex::machine::StepState;
+
+use crate::observers::callstack::{ShadowCallStack, StackEvent};
+use crate::observers::trace::TraceError;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionSymbol {
+    pub name: String,
+    pub start: u64,
+    pub end: u64,       // Exclusive
+}
+
+impl FunctionSymbol {
+    pub fn new<N: Into<String>>(name: N, start: u64, end: u64) -> Self {
+        Self { name: name.into(), start, end }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionProfile {
+    pub entry: u64,
+    pub name: Option<String>,
+    pub calls: u64,
+    pub exclusive_instructions: u64,
+    pub inclusive_instructions: u64,
+    pub exclusive_pcode_ops: u64,
+    pub inclusive_pcode_ops: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoopProfile {
+    pub function: u64,
+    pub head: u64,      // Target of the backward branch
+    pub latch: u64,     // Instruction taking the backward branch
+    pub iterations: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileReport {
+    pub total_instructions: u64,
+    pub total_pcode_ops: u64,
+    pub functions: Vec<FunctionProfile>,    // Hottest (exclusive instructions) first
+    pub loops: Vec<LoopProfile>,            // Hottest first
+}
+
+impl ProfileReport {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Write the report as a table sorted by exclusive instruction count
+    /// loops: number of hottest loops to list
+    pub fn write_table<W: Write + ?Sized>(&self, writer: &mut W, loops: usize) -> std::io::Result<()> {
+        writeln!(writer, "{} instructions, {} P-code operations", self.total_instructions, self.total_pcode_ops)?;
+        writeln!(writer, "{:>12} {:>7} {:>12} {:>7} {:>8}  function",
+            "exclusive", "%", "inclusive", "%", "calls")?;
+        let total = self.total_instructions.max(1) as f64;
+        for function in &self.functions {
+            let name = function.name.clone().unwrap_or_else(|| format!("{:#x}", function.entry));
+            writeln!(writer, "{:>12} {:>6.2}% {:>12} {:>6.2}% {:>8}  {}",
+                function.exclusive_instructions, function.exclusive_instructions as f64 * 100.0 / total,
+                function.inclusive_instructions, function.inclusive_instructions as f64 * 100.0 / total,
+                function.calls, name)?;
+        }
+        if loops != 0 && !self.loops.is_empty() {
+            writeln!(writer)?;
+            writeln!(writer, "{:>12}  {:>18} {:>18} {:>18}", "iterations", "function", "head", "latch")?;
+            for profile in self.loops.iter().take(loops) {
+                writeln!(writer, "{:>12}  {:#18x} {:#18x} {:#18x}", profile.iterations, profile.function, profile.head, profile.latch)?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counters {
+    calls: u64,
+    exclusive_instructions: u64,
+    inclusive_instructions: u64,
+    exclusive_pcode_ops: u64,
+    inclusive_pcode_ops: u64,
+}
+
+// A function being executed, to compute its inclusive counts when it returns
+#[derive(Debug, Clone, Copy)]
+struct OpenFrame {
+    function: u64,
+    instructions: u64,  // Totals when the function was entered
+    pcode_ops: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ProfilerState {
+    symbols: Vec<FunctionSymbol>,       // Sorted by start
+    stack: ShadowCallStack,
+    open: Vec<OpenFrame>,
+    on_stack: HashMap<u64, usize>,      // Number of open frames per function, for recursion
+    counters: HashMap<u64, Counters>,
+    loops: HashMap<(u64, u64, u64), u64>,   // (function, head, latch): iterations
+    instructions: u64,
+    pcode_ops: u64,
+    last: Option<(u64, u64)>,           // (address, fallthrough) of the last instruction
+}
+
+impl ProfilerState {
+    fn symbol(&self, address: u64) -> Option<&FunctionSymbol> {
+        let index = match self.symbols.binary_search_by(|symbol| symbol.start.cmp(&address)) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let symbol = &self.symbols[index];
+        if address < symbol.end { Some(symbol) } else { None }
+    }
+
+    // Functions are identified by the start of their symbol when there is one
+    fn function_of(&self, entry: u64) -> u64 {
+        self.symbol(entry).map(|symbol| symbol.start).unwrap_or(entry)
+    }
+
+    fn enter(&mut self, entry: u64, counted: bool) {
+        let function = self.function_of(entry);
+        if counted {
+            self.counters.entry(function).or_default().calls += 1;
+        }
+        *self.on_stack.entry(function).or_insert(0) += 1;
+        self.open.push(OpenFrame {
+            function,
+            instructions: self.instructions,
+            pcode_ops: self.pcode_ops,
+        });
+    }
+
+    fn leave(&mut self) {
+        if let Some(frame) = self.open.pop() {
+            let depth = self.on_stack.get_mut(&frame.function).unwrap();
+            *depth -= 1;
+            // Recursive calls are already covered by the outermost one
+            if *depth == 0 {
+                let counters = self.counters.entry(frame.function).or_default();
+                counters.inclusive_instructions += self.instructions - frame.instructions;
+                counters.inclusive_pcode_ops += self.pcode_ops - frame.pcode_ops;
+            }
+        }
+    }
+
+    fn step(&mut self, address: u64, step_state: &StepState, stack_pointer: Option<u64>) {
+        let pcode = step_state.operations();
+
+        match self.stack.step(address, pcode, stack_pointer) {
+            Some(StackEvent::Call(frame)) => self.enter(frame.function, true),
+            Some(StackEvent::Return(popped)) => popped.iter().for_each(|_| self.leave()),
+            Some(StackEvent::TailCall { replaced: _, function }) => {
+                self.leave();
+                self.enter(function, true);
+            },
+            None => (),
+        }
+        if self.open.is_empty() {
+            // Root frame of the shadow stack
+            self.enter(address, false);
+        }
+
+        let ops = pcode.operations().len() as u64;
+        self.instructions += 1;
+        self.pcode_ops += ops;
+
+        let current = match self.symbol(address) {
+            Some(symbol) => symbol.start,
+            None => self.open.last().unwrap().function,
+        };
+        let counters = self.counters.entry(current).or_default();
+        counters.exclusive_instructions += 1;
+        counters.exclusive_pcode_ops += ops;
+
+        // A taken backward branch inside the same function closes a loop iteration
+        if let Some((last, fallthrough)) = self.last {
+            if address < last && address != fallthrough && self.function_of(last) == self.function_of(address) {
+                *self.loops.entry((current, address, last)).or_insert(0) += 1;
+            }
+        }
+        self.last = Some((address, address + pcode.length() as u64));
+    }
+
+    fn report(&self) -> ProfileReport {
+        let mut counters = self.counters.clone();
+        // Functions still running are accounted up to now
+        let mut seen = HashSet::new();
+        for frame in &self.open {
+            if seen.insert(frame.function) {
+                let entry = counters.entry(frame.function).or_default();
+                entry.inclusive_instructions += self.instructions - frame.instructions;
+                entry.inclusive_pcode_ops += self.pcode_ops - frame.pcode_ops;
+            }
+        }
+
+        let mut functions: Vec<FunctionProfile> = counters.into_iter()
+            .map(|(entry, counters)| FunctionProfile {
+                entry,
+                name: self.symbol(entry).filter(|symbol| symbol.start == entry).map(|symbol| symbol.name.clone()),
+                calls: counters.calls,
+                exclusive_instructions: counters.exclusive_instructions,
+                inclusive_instructions: counters.inclusive_instructions,
+                exclusive_pcode_ops: counters.exclusive_pcode_ops,
+                inclusive_pcode_ops: counters.inclusive_pcode_ops,
+            })
+            .collect();
+        functions.sort_by(|a, b| b.exclusive_instructions.cmp(&a.exclusive_instructions).then(a.entry.cmp(&b.entry)));
+
+        let mut loops: Vec<LoopProfile> = self.loops.iter()
+            .map(|((function, head, latch), iterations)| LoopProfile {
+                function: *function,
+                head: *head,
+                latch: *latch,
+                iterations: *iterations,
+            })
+            .collect();
+        loops.sort_by(|a, b| b.iterations.cmp(&a.iterations).then(a.head.cmp(&b.head)));
+
+        ProfileReport {
+            total_instructions: self.instructions,
+            total_pcode_ops: self.pcode_ops,
+            functions,
+            loops,
+        }
+    }
+}
+
+/// Handle on the counters of a ProfilerHook
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    state: Arc<Mutex<ProfilerState>>,
+}
+
+impl Profiler {
+    /// Use a symbol table for function boundaries
+    /// Must be set before the emulation starts
+    pub fn set_symbols(&self, mut symbols: Vec<FunctionSymbol>) {
+        symbols.sort_by_key(|symbol| symbol.start);
+        let mut state = self.state.lock();
+        for symbol in &symbols {
+            state.stack.add_function(symbol.start);
+        }
+        state.symbols = symbols;
+    }
+
+    pub fn report(&self) -> ProfileReport {
+        self.state.lock().report()
+    }
+
+    /// Clear the counters, the symbol table is kept
+    pub fn reset(&self) {
+        let mut state = self.state.lock();
+        let symbols = std::mem::take(&mut state.symbols);
+        *state = ProfilerState::default();
+        for symbol in &symbols {
+            state.stack.add_function(symbol.start);
+        }
+        state.symbols = symbols;
+    }
+}
+
+/// Profiler observer
+/// S: State
+/// O: Order
+pub struct ProfilerHook<S, O> {
+    profiler: Profiler,
+    state: PhantomData<S>,
+    order: PhantomData<O>,
+}
+
+// NOTE: manual implementation avoids adding the trait bounds `S: Clone, O: Clone`.
+impl<S, O> Clone for ProfilerHook<S, O> {
+    fn clone(&self) -> Self {
+        Self {
+            profiler: self.profiler.clone(),
+            state: PhantomData,
+            order: PhantomData,
+        }
+    }
+}
+
+impl<S, O> ProfilerHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    pub fn new() -> (Profiler, Self) {
+        let profiler = Profiler::default();
+        let observer = Self {
+            profiler: profiler.clone(),
+            state: PhantomData,
+            order: PhantomData,
+        };
+        (profiler, observer)
+    }
+}
+
+impl<S: 'static, O> HookConcrete for ProfilerHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    type State = S;
+    type Error = TraceError;
+    type Outcome = String;
+
+    fn hook_architectural_step(&mut self, state: &mut Self::State, address: &Address, step_state: &StepState)
+        -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>
+    {
+        let stack_pointer = state.state_ref().stack_pointer_value().ok().map(u64::from);
+        self.profiler.state.lock().step(u64::from(address), step_state, stack_pointer);
+        Ok(HookStepAction::Pass.into())
+    }
+}
+
+impl<S: 'static, O> ClonableHookConcrete for ProfilerHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+}
@@ -0,0 +1,215 @@
+//! Cache of query outcomes
+//!
+//! Queries are keyed by a hash-consed form of their condition, expected
+//! value, inputs and assumptions: structurally equal expressions are the same consed
+//! term, so keys are hashed and compared by term id, without walking the
+//! expressions. A polling loop makes the same query on each iteration (the
+//! variables of a region are named after their varnode and load address), so
+//! the repeated polls are answered from the cache, without Boolector.
+//! The values of an input given by `enumerate` and `optimize` are cached the
+//! same way, keyed by the query, the address of the input and the selection.
+//! Unknown outcomes are not cached: the budget of the next query may be
+//! larger, or the query may have been refused while others were running.
+//! Terms no longer referenced are collected when the cache is emptied, and
+//! every `capacity` misses, as misses intern terms even if nothing is cached.
+use std::collections::HashMap;
+use hashconsing::{HConsed, HConsign, HashConsign};
+use fugue::bv::BitVec;
+use fugue::ir::Address;
+
+use super::{Objective, Query, SolveOutcome, ValuesOutcome};
+use super::expr::{BinOp, Expr, Node, UnOp};
+
+const DEFAULT_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Term {
+    Val(BitVec),
+    Var { name: String, bits: u32 },
+    Unary(UnOp, Consed),
+    Binary(BinOp, Consed, Consed),
+    Extract { expr: Consed, lsb: u32, msb: u32 },
+    Concat(Consed, Consed),
+    ZeroExtend(Consed, u32),
+    SignExtend(Consed, u32),
+    Ite(Consed, Consed, Consed),
+}
+
+type Consed = HConsed<Term>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    condition: Consed,
+    expected: u64,
+    inputs: Vec<(Consed, Address)>,
+    assumptions: Vec<Consed>,
+}
+
+/// Key of a query missing from the cache
+pub(super) struct CacheKey(Key);
+
+/// Values asked for an input of a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum Selection {
+    Optimize(Objective),
+    Enumerate(usize),   // Maximum number of values
+}
+
+/// Key of the values of an input missing from the cache
+pub(super) struct ValuesKey(Key, Address, Selection);
+
+/// Hits and misses since the cache was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+pub struct QueryCache {
+    terms: HConsign<Term>,
+    outcomes: HashMap<Key, SolveOutcome>,
+    values: HashMap<(Key, Address, Selection), ValuesOutcome>,
+    capacity: usize,        // Entries kept before the cache is emptied, outcomes and values together
+    hits: u64,
+    misses: u64,
+}
+
+impl Default for QueryCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            terms: HConsign::empty(),
+            outcomes: HashMap::new(),
+            values: HashMap::new(),
+            capacity: capacity.max(1),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        QueryCacheStats { hits: self.hits, misses: self.misses, entries: self.entries() }
+    }
+
+    fn entries(&self) -> usize {
+        self.outcomes.len() + self.values.len()
+    }
+
+    /// Forget the outcomes and values, and the terms only they referenced
+    pub fn clear(&mut self) {
+        self.outcomes.clear();
+        self.values.clear();
+        self.terms.collect_to_fit();
+    }
+
+    // Consed term of an expression, shared sub-expressions are consed once
+    fn cons(&mut self, root: &Expr, memo: &mut HashMap<*const Node, Consed>) -> Consed {
+        // Post-order, without recursion: conditions can be long chains
+        let mut stack = vec![(root.clone(), false)];
+        while let Some((expr, expanded)) = stack.pop() {
+            if memo.contains_key(&expr.id()) {
+                continue;
+            }
+            if !expanded {
+                stack.push((expr.clone(), true));
+                stack.extend(expr.children().into_iter().map(|child| (child.clone(), false)));
+                continue;
+            }
+            let consed = |child: &Expr| memo[&child.id()].clone();
+            let term = match expr.node() {
+                Node::Val(value) => Term::Val(value.clone()),
+                Node::Var { name, bits } => Term::Var { name: name.clone(), bits: *bits },
+                Node::Unary(op, operand) => Term::Unary(*op, consed(operand)),
+                Node::Binary(op, lhs, rhs) => Term::Binary(*op, consed(lhs), consed(rhs)),
+                Node::Extract { expr: operand, lsb, msb } => Term::Extract { expr: consed(operand), lsb: *lsb, msb: *msb },
+                Node::Concat(high, low) => Term::Concat(consed(high), consed(low)),
+                Node::ZeroExtend(operand, bits) => Term::ZeroExtend(consed(operand), *bits),
+                Node::SignExtend(operand, bits) => Term::SignExtend(consed(operand), *bits),
+                Node::Ite(condition, then, otherwise) => Term::Ite(consed(condition), consed(then), consed(otherwise)),
+            };
+            let consed = self.terms.mk(term);
+            memo.insert(expr.id(), consed);
+        }
+        memo[&root.id()].clone()
+    }
+
+    fn key(&mut self, query: &Query) -> Key {
+        let mut memo = HashMap::new();
+        let condition = self.cons(&query.condition, &mut memo);
+        let inputs = query.inputs.iter()
+            .map(|(input, address)| (self.cons(input, &mut memo), *address))
+            .collect();
+        let assumptions = query.assumptions.iter()
+            .map(|assumption| self.cons(assumption, &mut memo))
+            .collect();
+        Key { condition, expected: query.expected, inputs, assumptions }
+    }
+
+    // Count a miss, collecting the terms of the queries not cached
+    fn miss(&mut self) {
+        self.misses += 1;
+        if self.misses % self.capacity as u64 == 0 {
+            self.terms.collect_to_fit();
+        }
+    }
+
+    /// Outcome of the query if it was made before, and the key to `insert` its outcome otherwise
+    pub(super) fn lookup(&mut self, query: &Query) -> Result<SolveOutcome, CacheKey> {
+        let key = self.key(query);
+        match self.outcomes.get(&key) {
+            Some(outcome) => {
+                self.hits += 1;
+                Ok(outcome.clone())
+            },
+            None => {
+                self.miss();
+                Err(CacheKey(key))
+            },
+        }
+    }
+
+    /// Unknown outcomes are not kept
+    pub(super) fn insert(&mut self, key: CacheKey, outcome: SolveOutcome) {
+        if let SolveOutcome::Unknown(_) = outcome {
+            return;
+        }
+        if self.entries() >= self.capacity {
+            self.clear();
+        }
+        self.outcomes.insert(key.0, outcome);
+    }
+
+    /// Values of the input loaded from `address` if they were asked before, and the key to `insert_values` them otherwise
+    pub(super) fn lookup_values(&mut self, query: &Query, address: &Address, selection: Selection) -> Result<ValuesOutcome, ValuesKey> {
+        let key = (self.key(query), *address, selection);
+        match self.values.get(&key) {
+            Some(outcome) => {
+                self.hits += 1;
+                Ok(outcome.clone())
+            },
+            None => {
+                self.miss();
+                let (key, address, selection) = key;
+                Err(ValuesKey(key, address, selection))
+            },
+        }
+    }
+
+    /// Unknown outcomes are not kept
+    pub(super) fn insert_values(&mut self, key: ValuesKey, outcome: ValuesOutcome) {
+        if let ValuesOutcome::Unknown(_) = outcome {
+            return;
+        }
+        if self.entries() >= self.capacity {
+            self.clear();
+        }
+        let ValuesKey(key, address, selection) = key;
+        self.values.insert((key, address, selection), outcome);
+    }
+}
@@ -0,0 +1,223 @@
+//! Expressions built by the solver
+//!
+//! The solver builds its own expressions rather than `SymExpr` directly, so
+//! queries can be measured before they reach Boolector, and lowered to
+//! `SymExpr` only when solving. Sub-expressions are shared (`Arc`): the same
+//! varnode read twice is the same node, and sizes are counted in distinct
+//! nodes.
+//!
+//! Comparisons, carries and boolean operations produce P-code booleans: one
+//! byte holding 0 or 1.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use fugue::bv::BitVec;
+use fugue_concolic::expr::{SymExpr, IVar};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnOp {
+    Neg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    And,
+    Or,
+    Xor,
+    BoolAnd,
+    BoolXor,
+    Shl,
+    Shr,
+    Sar,
+    Add,
+    Sub,
+    Eq,
+    Ne,
+    Lt,
+    Slt,
+    Carry,
+    SCarry,
+}
+
+impl BinOp {
+    /// Operations producing a P-code boolean
+    pub fn is_boolean(&self) -> bool {
+        matches!(self,
+            BinOp::BoolAnd | BinOp::BoolXor
+            | BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Slt
+            | BinOp::Carry | BinOp::SCarry)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Node {
+    Val(BitVec),
+    Var { name: String, bits: u32 },
+    Unary(UnOp, Expr),
+    Binary(BinOp, Expr, Expr),
+    Extract { expr: Expr, lsb: u32, msb: u32 },   // Bits [lsb, msb)
+    Concat(Expr, Expr),                             // High, low
+    ZeroExtend(Expr, u32),
+    SignExtend(Expr, u32),
+    Ite(Expr, Expr, Expr),
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Expr(Arc<Node>);
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Expr {
+    pub fn new(node: Node) -> Self {
+        Self(Arc::new(node))
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.0
+    }
+
+    pub fn val(value: BitVec) -> Self {
+        Self::new(Node::Val(value))
+    }
+
+    pub fn val_u64(value: u64, bits: u32) -> Self {
+        Self::val(BitVec::from_u64(value, bits as usize))
+    }
+
+    pub fn var<N: Into<String>>(name: N, bits: u32) -> Self {
+        Self::new(Node::Var { name: name.into(), bits })
+    }
+
+    pub fn unary(op: UnOp, expr: Expr) -> Self {
+        Self::new(Node::Unary(op, expr))
+    }
+
+    pub fn binary(op: BinOp, lhs: Expr, rhs: Expr) -> Self {
+        Self::new(Node::Binary(op, lhs, rhs))
+    }
+
+    pub fn extract(expr: Expr, lsb: u32, msb: u32) -> Self {
+        Self::new(Node::Extract { expr, lsb, msb })
+    }
+
+    pub fn concat(high: Expr, low: Expr) -> Self {
+        Self::new(Node::Concat(high, low))
+    }
+
+    pub fn zero_extend(expr: Expr, bits: u32) -> Self {
+        Self::new(Node::ZeroExtend(expr, bits))
+    }
+
+    pub fn sign_extend(expr: Expr, bits: u32) -> Self {
+        Self::new(Node::SignExtend(expr, bits))
+    }
+
+    pub fn ite(condition: Expr, then: Expr, otherwise: Expr) -> Self {
+        Self::new(Node::Ite(condition, then, otherwise))
+    }
+
+    pub fn bits(&self) -> u32 {
+        match self.node() {
+            Node::Val(value) => value.bits() as u32,
+            Node::Var { bits, .. } => *bits,
+            Node::Unary(_, expr) => expr.bits(),
+            Node::Binary(op, _, _) if op.is_boolean() => 8,
+            Node::Binary(_, lhs, _) => lhs.bits(),
+            Node::Extract { lsb, msb, .. } => msb - lsb,
+            Node::Concat(high, low) => high.bits() + low.bits(),
+            Node::ZeroExtend(_, bits) | Node::SignExtend(_, bits) => *bits,
+            Node::Ite(_, then, _) => then.bits(),
+        }
+    }
+
+    pub fn children(&self) -> Vec<&Expr> {
+        match self.node() {
+            Node::Val(_) | Node::Var { .. } => Vec::new(),
+            Node::Unary(_, expr)
+            | Node::Extract { expr, .. }
+            | Node::ZeroExtend(expr, _)
+            | Node::SignExtend(expr, _) => vec![expr],
+            Node::Binary(_, lhs, rhs) => vec![lhs, rhs],
+            Node::Concat(high, low) => vec![high, low],
+            Node::Ite(condition, then, otherwise) => vec![condition, then, otherwise],
+        }
+    }
+
+    // Identity of the shared node
+    pub(crate) fn id(&self) -> *const Node {
+        Arc::as_ptr(&self.0)
+    }
+
+    /// Distinct nodes of the expressions, shared sub-expressions are counted once
+    pub fn node_count(exprs: &[&Expr]) -> usize {
+        let mut seen = HashSet::new();
+        let mut stack: Vec<&Expr> = exprs.to_vec();
+        while let Some(expr) = stack.pop() {
+            if seen.insert(expr.id()) {
+                stack.extend(expr.children());
+            }
+        }
+        seen.len()
+    }
+}
+
+/// Lowering to `SymExpr`, shared nodes are lowered once and variables of the
+/// same name are the same `IVar`
+#[derive(Default)]
+pub(crate) struct Lowering {
+    nodes: HashMap<*const Node, SymExpr>,
+    vars: HashMap<String, SymExpr>,
+}
+
+impl Lowering {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn lower(&mut self, expr: &Expr) -> SymExpr {
+        if let Some(lowered) = self.nodes.get(&expr.id()) {
+            return lowered.clone();
+        }
+        let lowered = match expr.node() {
+            Node::Val(value) => SymExpr::val(value.clone()),
+            Node::Var { name, bits } => self.vars.entry(name.clone())
+                .or_insert_with(|| SymExpr::ivar(IVar::new_named(name, *bits)))
+                .clone(),
+            Node::Unary(UnOp::Neg, expr) => SymExpr::neg(self.lower(expr)),
+            Node::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (self.lower(lhs), self.lower(rhs));
+                match op {
+                    BinOp::And => SymExpr::and(lhs, rhs),
+                    BinOp::Or => SymExpr::or(lhs, rhs),
+                    BinOp::Xor => SymExpr::xor(lhs, rhs),
+                    BinOp::BoolAnd => SymExpr::bool_and(lhs, rhs),
+                    BinOp::BoolXor => SymExpr::bool_xor(lhs, rhs),
+                    BinOp::Shl => SymExpr::shl(lhs, rhs),
+                    BinOp::Shr => SymExpr::shr(lhs, rhs),
+                    BinOp::Sar => SymExpr::signed_shr(lhs, rhs),
+                    BinOp::Add => SymExpr::add(lhs, rhs),
+                    BinOp::Sub => SymExpr::sub(lhs, rhs),
+                    BinOp::Eq => SymExpr::eq(lhs, rhs),
+                    BinOp::Ne => SymExpr::ne(lhs, rhs),
+                    BinOp::Lt => SymExpr::lt(lhs, rhs),
+                    BinOp::Slt => SymExpr::slt(lhs, rhs),
+                    BinOp::Carry => SymExpr::carry(lhs, rhs),
+                    BinOp::SCarry => SymExpr::signed_carry(lhs, rhs),
+                }
+            },
+            Node::Extract { expr, lsb, msb } => SymExpr::extract(self.lower(expr), *lsb, *msb),
+            Node::Concat(high, low) => SymExpr::concat(self.lower(high), self.lower(low)),
+            Node::ZeroExtend(expr, bits) => SymExpr::zero_extend(self.lower(expr), *bits),
+            Node::SignExtend(expr, bits) => SymExpr::sign_extend(self.lower(expr), *bits),
+            Node::Ite(condition, then, otherwise) => {
+                SymExpr::ite(self.lower(condition), self.lower(then), self.lower(otherwise))
+            },
+        };
+        self.nodes.insert(expr.id(), lowered.clone());
+        lowered
+    }
+}
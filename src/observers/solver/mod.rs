@@ -0,0 +1,1244 @@
+//! Constraint solver for P-code regions
+//!
+//! `ConstraintSolver` builds symbolic expressions for the P-code operations
+//! of a region (e.g. a peripheral polling loop) and solves them for the
+//! symbolic inputs of the region. The lifecycle is:
+//!
+//! 1. `mark_symbolic` the memory ranges whose loads are inputs (every load
+//!    is an input when no range is marked), `set_default_variables` for the
+//!    registers with a fixed value;
+//! 2. `start_region` before the first operation of the region;
+//! 3. `add_pcode` for each operation executed in the region, with the state
+//!    before the operation;
+//! 4. `solve` for the value a condition must take, e.g. the condition of
+//!    the branch to reach, which returns a value for each input, or
+//!    `SolveOutcome::Unknown` when the query exceeds its `SolverLimits`;
+//!    `enumerate` and `optimize` give several values of an input, or its
+//!    unsigned minimum or maximum;
+//! 5. `reset` (or `start_region` again) to forget the region; marked ranges
+//!    and default variables are kept.
+//!
+//! Memory is a map from concrete addresses to the values stored in the
+//! region, initialised from the state on first access, so values stored to
+//! RAM and loaded back keep their dependence on the inputs. Accesses through
+//! a pointer depending on an input fall back to an if-then-else over the
+//! cells stored to in the region, the concrete address being the default.
+//!
+//! Queries are simplified before solving (constant folding, redundant
+//! extends and extracts, boolean normalisation), see `simplify`.
+//!
+//! Queries run on a thread with a large stack. Boolector cannot be
+//! interrupted, so a query over budget keeps its thread running until it
+//! returns; while too many of them are (see `abandoned_queries`), new queries
+//! with a time or memory limit are refused as `LimitExceeded::AbandonedQueries`.
+//!
+//! Outcomes, and the values given by `enumerate` and `optimize`, are cached
+//! by query (see `QueryCache`), so the identical queries made on each
+//! iteration of a polling loop are solved once. Clones of a
+//! solver share its cache.
+//!
+//! Each query can be exported as SMT-LIB2 (`Query::to_smtlib`), to be
+//! replayed with another solver.
+//!
+//! Errors are reported as `SolverError`; the region should be abandoned on
+//! error. With `UnsupportedOpPolicy::Concretize`, operations that cannot be
+//! modelled are given their concrete values instead, with a warning, and
+//! counted in `concretization_stats`.
+use fugue_concolic_solver_boolector::SolverContext;
+use std::marker::PhantomData;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use parking_lot::Mutex;
+use std::fmt;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use log;
+use thiserror::Error;
+
+use fuguex::state::{
+    pcode::PCodeState, };
+use fugue::bytes::{Order};
+use fugue::bv::BitVec;
+
+use fugue::ir::{
+    Address,
+    il::pcode::{Operand, PCodeOp}
+};
+
+use crate::observers::pcode_trace::decompose;
+
+mod expr;
+pub use expr::{BinOp, Expr, Node, UnOp};
+use expr::Lowering;
+
+mod cache;
+pub use cache::{QueryCache, QueryCacheStats};
+use cache::Selection;
+
+mod simplify;
+pub use simplify::simplify;
+
+mod smtlib;
+
+mod varnode;
+use varnode::{Space, VarnodeStore};
+
+#[derive(Debug, Error)]
+pub enum SolverError {
+    #[error("no region started")]
+    NotStarted,
+    #[error("PC {pc:#x}: operation not supported by the solver: {operation}")]
+    UnsupportedOperation { pc: u64, operation: String },
+    #[error("constant operand {0} cannot be written")]
+    ConstantOperand(String),
+    #[error("operand {0} has no value, and is not a default variable")]
+    UnknownVariable(String),
+    #[error("unsupported operand size: {0} bytes")]
+    UnsupportedSize(usize),
+    #[error("state access failed: {0}")]
+    State(String),
+    #[error("no symbolic input in the region")]
+    NoSymbolicInput,
+    #[error("solver query failed: {0}")]
+    Query(String),
+}
+
+impl SolverError {
+    /// Errors caused by P-code the solver cannot model, rather than by its use
+    pub fn is_unsupported(&self) -> bool {
+        matches!(self,
+            SolverError::UnsupportedOperation { .. }
+            | SolverError::UnknownVariable(_)
+            | SolverError::UnsupportedSize(_)
+            | SolverError::State(_))
+    }
+}
+
+/// Values of the symbolic inputs, None for the inputs without solution
+/// Values have the width of the load that created the input, e.g. 128 bits for a SIMD load
+pub type SolveResult = HashMap<Address, Option<BitVec>>;
+
+/// A solving query: the inputs making `condition` equal to `expected`
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub condition: Expr,
+    pub expected: u64,
+    pub inputs: Vec<(Expr, Address)>,      // Variables to solve, with the address they were loaded from
+    pub assumptions: Vec<Expr>,             // Booleans that must hold as well, e.g. the value of an input
+    pub original_nodes: usize,              // Nodes of the condition before simplification
+}
+
+impl Query {
+    /// The query as an SMT-LIB2 script, asserting the condition and getting the values of the inputs
+    pub fn to_smtlib(&self) -> String {
+        let mut script = String::new();
+        smtlib::write_query(self, &mut script).expect("writing to a String cannot fail");
+        script
+    }
+
+    /// The same query, with the input loaded from `address` fixed to `value`
+    pub fn with_value(&self, address: &Address, value: &BitVec) -> Result<Query, SolverError> {
+        let input = self.input(address)?;
+        let mut query = self.clone();
+        query.assumptions.push(Expr::binary(BinOp::Eq, input.clone(), Expr::val(value.clone())));
+        Ok(query)
+    }
+
+    /// Variable of the input loaded from `address`
+    pub fn input(&self, address: &Address) -> Result<&Expr, SolverError> {
+        self.inputs.iter()
+            .find(|(_, input_address)| input_address == address)
+            .map(|(input, _)| input)
+            .ok_or_else(|| SolverError::UnknownVariable(format!("{}", address)))
+    }
+
+    /// Distinct nodes of the condition
+    pub fn node_count(&self) -> usize {
+        Expr::node_count(&[&self.condition])
+    }
+
+    /// Nodes removed by the simplification, in percent of the original condition
+    pub fn shrink_percent(&self) -> f64 {
+        if self.original_nodes == 0 {
+            return 0.0;
+        }
+        100.0 * self.original_nodes.saturating_sub(self.node_count()) as f64 / self.original_nodes as f64
+    }
+}
+
+/// Outcome of a query
+#[derive(Debug, Clone)]
+pub enum SolveOutcome {
+    Solved(SolveResult),
+    /// A budget was exceeded before the solver answered
+    Unknown(LimitExceeded),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Time(Duration),
+    Memory(usize),          // Bytes used by the query when it was abandoned
+    ExpressionSize(usize),  // Nodes of the query
+    AbandonedQueries(usize),    // Queries over budget still running, the query was not started
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Time(timeout) => write!(f, "timed out after {:?}", timeout),
+            LimitExceeded::Memory(bytes) => write!(f, "used {} bytes of memory", bytes),
+            LimitExceeded::ExpressionSize(nodes) => write!(f, "expression of {} nodes", nodes),
+            LimitExceeded::AbandonedQueries(running) => write!(f, "{} abandoned queries still running", running),
+        }
+    }
+}
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_NODES: usize = 100_000;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const QUERY_STACK_SIZE: usize = 64 * 1024 * 1024;   // Expressions are lowered recursively
+const MAX_ABANDONED_QUERIES: usize = 4;     // Queries over budget left running before new ones are refused
+
+// Queries over budget whose thread is still running, in the whole process
+static ABANDONED_QUERIES: AtomicUsize = AtomicUsize::new(0);
+
+/// Queries abandoned over budget whose solver thread is still running
+/// Past `MAX_ABANDONED_QUERIES`, queries with a time or memory limit are not started
+pub fn abandoned_queries() -> usize {
+    ABANDONED_QUERIES.load(Ordering::Acquire)
+}
+
+/// Budgets of a query, None for no limit
+/// `max_memory` is a process-wide guard: the growth of the resident memory is charged to the
+/// query, whoever allocated it, e.g. other emulator threads. It is not checked while abandoned
+/// queries are still running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SolverLimits {
+    pub timeout: Option<Duration>,
+    pub max_memory: Option<usize>,  // Growth of the resident memory of the process during the query, in bytes
+    pub max_nodes: Option<usize>,   // Distinct nodes of the query, checked before solving
+}
+
+impl Default for SolverLimits {
+    fn default() -> Self {
+        Self {
+            timeout: Some(DEFAULT_TIMEOUT),
+            max_memory: None,
+            max_nodes: Some(DEFAULT_MAX_NODES),
+        }
+    }
+}
+
+/// Values of one input over the models of a query
+#[derive(Debug, Clone)]
+pub enum ValuesOutcome {
+    /// Distinct values, in the order found, empty if the query has no solution
+    Values(Vec<BitVec>),
+    /// A budget was exceeded before the solver answered
+    Unknown(LimitExceeded),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Objective {
+    Minimize,
+    Maximize,
+}
+
+/// What `add_pcode` does with an operation it cannot model
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnsupportedOpPolicy {
+    /// Return the error, the region should be abandoned
+    #[default]
+    Error,
+    /// Give the outputs of the operation their concrete value, and go on
+    /// Variables never written to also take their concrete value
+    Concretize,
+}
+
+/// Number of concretizations made under `UnsupportedOpPolicy::Concretize`, since the solver was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConcretizationStats {
+    pub operations: u64,
+    pub symbolic_operations: u64,   // Operations whose inputs depended on a symbolic input
+    pub variables: u64,
+}
+
+type StateValueType = u8;
+
+#[derive(Clone)]
+pub struct ConstraintSolver <O: Order> {
+    default_variables : HashMap<String, u128>,      // <Name of the default variable>: <value of the default variable>
+    symbolic_ranges: Vec<(Address, Address)>,       // Inclusive ranges whose loads are inputs, every load if empty
+    started: bool,
+    store: VarnodeStore,                            // Values of the registers, temporaries and memory
+    stores: Vec<(u64, usize)>,                      // Memory cells (address, size) stored to in the region
+    reading_symbolic: bool,                         // Operands read by the current operation depend on an input
+    unsupported_policy: UnsupportedOpPolicy,
+    stats: ConcretizationStats,
+    limits: SolverLimits,
+    simplify: bool,                                 // Simplify the queries before solving
+    cache: Option<Arc<Mutex<QueryCache>>>,          // Outcomes of the previous queries, shared by the clones
+    var_to_solve: HashMap<String, (Expr, Address)>,    // The variable to be solved, added when load happens
+                                                                        //<Name of the variable>:(Symbex::variable, Address of the regisiter)
+    order: PhantomData<O>,
+}
+
+impl<O: Order> fmt::Debug for ConstraintSolver<O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)
+    -> std::result::Result<(), std::fmt::Error> {
+        let inputs: Vec<(&String, &Address)> = self.var_to_solve.iter()
+            .map(|(name, (_, address))| (name, address))
+            .collect();
+        f.debug_struct("ConstraintSolver")
+         .field("started", &self.started)
+         .field("inputs", &inputs)
+         .field("symbolic_ranges", &self.symbolic_ranges)
+         .field("stores", &self.stores)
+         .field("default_variables", &self.default_variables)
+         .field("unsupported_policy", &self.unsupported_policy)
+         .field("limits", &self.limits)
+         .field("simplify", &self.simplify)
+         .field("query_cache", &self.query_cache_stats())
+         .finish()
+    }
+}
+
+impl<O: Order> Default for ConstraintSolver<O> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <O> ConstraintSolver <O>
+where O: Order
+{
+    pub fn new() -> Self {
+        Self{
+            default_variables: HashMap::new(),
+            symbolic_ranges: Vec::new(),
+            started: false,
+            store: VarnodeStore::new(!O::ENDIAN.is_little()),
+            stores: Vec::new(),
+            reading_symbolic: false,
+            unsupported_policy: UnsupportedOpPolicy::default(),
+            stats: ConcretizationStats::default(),
+            limits: SolverLimits::default(),
+            simplify: true,
+            cache: Some(Arc::new(Mutex::new(QueryCache::default()))),
+            var_to_solve: HashMap::new(),
+            order: PhantomData,
+        }
+    }
+
+    /// Registers (by name) with a fixed value inside the region
+    pub fn set_default_variables(&mut self, vars: &HashMap<String, u128>){
+        self.default_variables = vars.clone();
+    }
+
+    /// Loads from [start, end] become symbolic inputs
+    /// Loads from other addresses read the concrete value, once a range is marked
+    pub fn mark_symbolic<A>(&mut self, start: A, end: A) where A: Into<Address> {
+        self.symbolic_ranges.push((start.into(), end.into()));
+    }
+
+    pub fn clear_symbolic(&mut self) {
+        self.symbolic_ranges.clear();
+    }
+
+    fn is_symbolic(&self, address: &Address) -> bool {
+        self.symbolic_ranges.is_empty()
+            || self.symbolic_ranges.iter().any(|(min, max)| min <= address && address <= max)
+    }
+
+    /// Start a region, forgetting the previous one
+    pub fn start_region(&mut self) {
+        self.reset();
+        self.started = true;
+    }
+
+    /// Forget the current region, the configuration is kept
+    pub fn reset(&mut self) {
+        self.started = false;
+        self.store.clear();
+        self.stores.clear();
+        self.var_to_solve.clear();
+    }
+
+    pub fn set_unsupported_policy(&mut self, policy: UnsupportedOpPolicy) {
+        self.unsupported_policy = policy;
+    }
+
+    pub fn concretization_stats(&self) -> ConcretizationStats {
+        self.stats
+    }
+
+    pub fn set_limits(&mut self, limits: SolverLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> SolverLimits {
+        self.limits
+    }
+
+    /// Simplify the queries before solving, enabled by default
+    pub fn set_simplify(&mut self, enable: bool) {
+        self.simplify = enable;
+    }
+
+    /// Answer repeated queries from a cache, enabled by default
+    pub fn set_query_cache(&mut self, enable: bool) {
+        if !enable {
+            self.cache = None;
+        } else if self.cache.is_none() {
+            self.cache = Some(Arc::new(Mutex::new(QueryCache::default())));
+        }
+    }
+
+    /// Share the cache of another solver, e.g. of another peripheral
+    pub fn share_query_cache(&mut self, cache: Arc<Mutex<QueryCache>>) {
+        self.cache = Some(cache);
+    }
+
+    pub fn query_cache(&self) -> Option<Arc<Mutex<QueryCache>>> {
+        self.cache.clone()
+    }
+
+    /// Hits and misses of the cache, zero if it is disabled
+    pub fn query_cache_stats(&self) -> QueryCacheStats {
+        self.cache.as_ref().map(|cache| cache.lock().stats()).unwrap_or_default()
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// Addresses of the symbolic inputs loaded in the region
+    pub fn inputs(&self) -> impl Iterator<Item = &Address> {
+        self.var_to_solve.values().map(|(_, address)| address)
+    }
+
+    fn var_list_insert(&mut self, operand: &Operand, expr: Option<Expr>) -> Result<Expr, SolverError>{
+        // Write the value to the bytes of the varnode, overlapping varnodes see the new bytes
+        // If the value of the variable is not specified, then create new one
+        // A computed value depends on an input if one of the operands read does
+        let (expr, symbolic) = match expr {
+            Some(expr) => (expr, self.reading_symbolic),
+            None => (self.store.fresh(operand)?, true),
+        };
+        self.store.write(operand, &expr, symbolic)?;
+        Ok(expr)
+    }
+
+    fn var_list_get(&mut self, state: &PCodeState<StateValueType, O>, operand: &Operand) -> Result<Expr, SolverError> {
+        // If every byte has been previously written to, then return their value
+        if self.store.is_known(operand)? != Some(true) {
+            // Bytes never written to keep their value from before the region
+            let initial = self.initial_value(state, operand)?;
+            self.store.fill(operand, &initial, false)?;
+        }
+        let (expr, symbolic) = self.store.read(operand)?.unwrap();
+        self.reading_symbolic |= symbolic;
+        Ok(expr)
+    }
+
+    // Symbolic value of a pointer and whether it depends on an input
+    // None if it cannot be read, e.g. a temporary computed before the region started
+    fn read_pointer(&mut self, state: &PCodeState<StateValueType, O>, pointer: &Operand) -> (Option<Expr>, bool) {
+        let reading_symbolic = std::mem::replace(&mut self.reading_symbolic, false);
+        let expr = self.symexpr_from_operand_read(state, pointer).ok();
+        let symbolic = std::mem::replace(&mut self.reading_symbolic, reading_symbolic);
+        (expr, symbolic)
+    }
+
+    // Value of the memory at a concrete address, from the stores of the region or the state
+    fn memory_read(&mut self, state: &PCodeState<StateValueType, O>, address: &Address, size: usize) -> Result<Expr, SolverError> {
+        let offset = u64::from(*address);
+        if self.store.is_known_at(&Space::Memory, offset, size) != Some(true) {
+            let concrete = self.concrete_load(state, address, size)?;
+            self.store.fill_at(&Space::Memory, offset, size, &concrete, false);
+        }
+        let (expr, symbolic) = self.store.read_at(&Space::Memory, offset, size).unwrap();
+        self.reading_symbolic |= symbolic;
+        Ok(expr)
+    }
+
+    // Forget the memory written concretely, and the cells stored to it overlaps
+    fn forget_memory(&mut self, offset: u64, size: usize) {
+        self.store.invalidate_at(&Space::Memory, offset, size);
+        let end = offset + size as u64;
+        self.stores.retain(|(cell, cell_size)| end <= *cell || *cell + *cell_size as u64 <= offset);
+    }
+
+    // Cells of `size` bytes stored to in the region, other than `offset`
+    fn other_cells(&self, offset: u64, size: usize) -> Vec<u64> {
+        self.stores.iter()
+            .filter(|(cell, cell_size)| *cell != offset && *cell_size == size)
+            .map(|(cell, _)| *cell)
+            .collect()
+    }
+
+    // Value of a varnode before the region
+    fn initial_value(&mut self, state: &PCodeState<StateValueType, O>, operand: &Operand) -> Result<Expr, SolverError> {
+        let var_name = VarnodeStore::name(operand)?;
+
+        if let Some(default_value) = self.default_variables.get(&var_name).cloned() {
+            // Check if it is in the default variable list
+            // Create a constant based on its default value
+            log::trace!("Variable({:?}) found in the default variable list", operand);
+            let operand_size_bits = operand.size() * 8;
+            return Ok(Expr::val(BitVec::from_u128(default_value, operand_size_bits)));
+        }
+
+        // If not in the default variable list and it's a regisiter
+        // Then get the concrete value from the regisiter
+        if let Operand::Register { .. } = operand {
+            return self.concrete_operand(state, operand);
+        }
+
+        if self.unsupported_policy == UnsupportedOpPolicy::Concretize {
+            log::warn!("Variable({:?}) never written in the region, concretized", operand);
+            self.stats.variables += 1;
+            return match operand {
+                Operand::Address { value, size } => self.concrete_load(state, value, *size),
+                _ => self.concrete_operand(state, operand),
+            };
+        }
+
+        log::debug!("Variable({:?}), name({:?}) never written, default_vars: {:?}", operand, var_name, self.default_variables);
+        Err(SolverError::UnknownVariable(var_name))
+    }
+
+    // Concrete value of a register or temporary in the state
+    fn concrete_operand(&self, state: &PCodeState<StateValueType, O>, operand: &Operand) -> Result<Expr, SolverError> {
+        let bytes = state.with_operand_values(operand, |values| values.to_vec())
+            .map_err(|e| SolverError::State(format!("{:?}", e)))?;
+        Ok(Self::concrete_value(&bytes))
+    }
+
+    // Constant of any width from bytes in the byte order of the state
+    fn concrete_value(bytes: &[u8]) -> Expr {
+        Expr::val(BitVec::from_bytes::<O>(bytes, false))
+    }
+
+    // Whether one of the inputs of the operation depends on a symbolic input
+    fn depends_on_input(&self, instruction: &PCodeOp) -> bool {
+        let (_, inputs, _) = decompose(instruction);
+        inputs.iter().any(|input| {
+            matches!(self.store.read(input), Ok(Some((_, true))))
+        })
+    }
+
+    // Generate the corresponding expression for reading Operand operations
+    fn symexpr_from_operand_read(&mut self, state: &PCodeState<StateValueType, O>, operand: &Operand) -> Result<Expr, SolverError> {
+        match operand {
+            Operand::Constant { value, size } => {
+                // byte size to bit size
+                Ok(Expr::val_u64(*value, *size as u32 * 8))     // TODO: Check if we can use BitVec in the Operand here
+            }
+            _ => {
+                self.var_list_get(state, operand)
+            }
+        }
+    }
+
+    // Concrete value of a load outside the symbolic ranges
+    fn concrete_load(&self, state: &PCodeState<StateValueType, O>, address: &Address, size: usize) -> Result<Expr, SolverError> {
+        let mut bytes = vec![0u8; size];
+        state.get_values(*address, &mut bytes)
+            .map_err(|e| SolverError::State(format!("{:?}", e)))?;
+        Ok(Self::concrete_value(&bytes))
+    }
+
+
+    /// Add the next operation of the region
+    /// state: state before the operation is executed
+    /// Operations that cannot be modelled are errors, or concretized, depending on the
+    /// UnsupportedOpPolicy
+    pub fn add_pcode(&mut self, instruction: &PCodeOp, state: &PCodeState<StateValueType, O>) -> Result<(), SolverError>{
+        if !self.started {
+            return Err(SolverError::NotStarted);
+        }
+        match self.model_pcode(instruction, state) {
+            Err(e) if e.is_unsupported() && self.unsupported_policy == UnsupportedOpPolicy::Concretize => {
+                let symbolic = self.depends_on_input(instruction);
+                if symbolic {
+                    log::warn!("{}, concretized: the solution may be missed, the operation depends on a symbolic input", e);
+                    self.stats.symbolic_operations += 1;
+                } else {
+                    log::warn!("{}, concretized", e);
+                }
+                self.stats.operations += 1;
+                self.add_pcode_concrete(instruction, state)
+            },
+            result => result,
+        }
+    }
+
+    fn model_pcode(&mut self, instruction: &PCodeOp, state: &PCodeState<StateValueType, O>) -> Result<(), SolverError>{
+        self.reading_symbolic = false;
+        match instruction.clone(){
+            // Move
+            PCodeOp::Load{source, destination, space: _} => {
+                // The source is the pointer, its concrete value is the address loaded from
+                let source_address = state.get_address(&source)
+                    .map_err(|e| SolverError::State(format!("{:?}", e)))?; // Read the real address
+                let size = destination.size();
+
+                let value = if self.is_symbolic(&source_address) {
+                    // When loading a variable from target memory, create a new variable to solve
+                    let name = format!("{}", source_address);
+                    let input = self.store.fresh_named(name.clone(), size as u32 * 8);
+                    // Mark it as a target variable to be solved
+                    log::debug!("Insert variable: {}", name);
+                    self.var_to_solve.insert(name, (input.clone(), source_address));
+                    self.reading_symbolic = true;
+                    input
+                } else {
+                    let mut value = self.memory_read(state, &source_address, size)?;
+                    // A pointer depending on an input may designate any cell stored to in the region
+                    if let (Some(pointer), true) = self.read_pointer(state, &source) {
+                        for cell in self.other_cells(u64::from(source_address), size) {
+                            let (cell_value, symbolic) = self.store.read_at(&Space::Memory, cell, size).unwrap();
+                            let cell_address = Expr::val_u64(cell, pointer.bits());
+                            value = Expr::ite(Expr::binary(BinOp::Eq, pointer.clone(), cell_address), cell_value, value);
+                            self.reading_symbolic |= symbolic;
+                        }
+                        self.reading_symbolic = true;
+                    }
+                    value
+                };
+
+                // Creat dest and load src into it
+                self.var_list_insert(&destination, Some(value))?;
+
+                log::trace!("Load: {:?} <- {:?}", destination, source);
+            },
+            PCodeOp::Copy{source, destination} => {
+                let src_sym = self.symexpr_from_operand_read(state, &source)?;
+                self.var_list_insert(&destination, Some(src_sym))?;
+            },
+
+            // Branch
+            PCodeOp::CBranch { destination: _, condition: _ } =>{
+                // The final expression to be solved
+                // let op_sym = self.symexpr_from_operand_read(&condition);
+                // self.exp_to_solve.push(op_sym.clone());
+
+            },
+            PCodeOp::Return { destination: _ } =>{
+                // No effect for building the tree
+            },
+            PCodeOp::Call { destination: _} => {
+               // No effect for building the tree
+            },
+            PCodeOp::ICall { destination: _ } => {
+               // No effect for building the tree, the callee is followed by the caller of the solver
+            },
+            PCodeOp::Branch { destination: _ } => {
+               // No effect for building the tree
+            },
+            PCodeOp::Store { source, destination, space: _ } => {
+                // The destination is the pointer, its concrete value is the address stored to
+                let address = state.get_address(&destination)
+                    .map_err(|e| SolverError::State(format!("{:?}", e)))?;
+                let (offset, size) = (u64::from(address), source.size());
+                let src_sym = self.symexpr_from_operand_read(state, &source)?;
+
+                // A pointer depending on an input may designate any other cell stored to in the region
+                if let (Some(pointer), true) = self.read_pointer(state, &destination) {
+                    for cell in self.other_cells(offset, size) {
+                        let (cell_value, _) = self.store.read_at(&Space::Memory, cell, size).unwrap();
+                        let cell_address = Expr::val_u64(cell, pointer.bits());
+                        let updated = Expr::ite(Expr::binary(BinOp::Eq, pointer.clone(), cell_address), src_sym.clone(), cell_value);
+                        self.store.write_at(&Space::Memory, cell, size, &updated, true);
+                    }
+                    self.reading_symbolic = true;
+                }
+
+                self.store.write_at(&Space::Memory, offset, size, &src_sym, self.reading_symbolic);
+                if !self.stores.contains(&(offset, size)) {
+                    self.stores.push((offset, size));
+                }
+            },
+            ////////////////////////////////////////////////
+            // Bitwise Operations
+            PCodeOp::IntAnd{result, operands} => {
+                // get the operands
+                let op1 = &operands[0];
+                let op2 = &operands[1];
+
+                let op1_sym = self.symexpr_from_operand_read(state, op1)?;
+                let op2_sym = self.symexpr_from_operand_read(state, op2)?;
+
+                let result_sym = Expr::binary(BinOp::And, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+
+            },
+            PCodeOp::BoolAnd { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+                let result_sym = Expr::binary(BinOp::BoolAnd, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+
+            },
+            PCodeOp::IntOr { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+
+                let result_sym = Expr::binary(BinOp::Or, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+
+            },
+            PCodeOp::BoolXor { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+
+                let result_sym = Expr::binary(BinOp::BoolXor, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::IntXor { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+
+                let result_sym = Expr::binary(BinOp::Xor, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::IntLeftShift { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+                let result_sym = Expr::binary(BinOp::Shl, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::IntRightShift { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+                let result_sym = Expr::binary(BinOp::Shr, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::IntSRightShift { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+
+                let result_sym = Expr::binary(BinOp::Sar, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            ////////////////////////////////////////////////
+            // Change size
+            PCodeOp::IntZExt { result, operand } => {
+                let op_sym = self.symexpr_from_operand_read(state, &operand)?;
+                let result_sym = Expr::zero_extend(op_sym, result.size() as u32*8); // Byte count to bit count
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::IntSExt { result, operand } => {
+                let op_sym = self.symexpr_from_operand_read(state, &operand)?;
+                let result_sym = Expr::sign_extend(op_sym, result.size() as u32*8); // Byte count to bit count
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::Subpiece { result, operand, amount } => {
+                let op_sym = self.symexpr_from_operand_read(state, &operand)?;
+                // Parse the *amount* argument and extract bits in *operands* according to it
+                if let Operand::Constant { value, size: _ } = amount {
+                    // Fill up to the size of the output,
+                    // so get the smaller size between the amount and the result
+                    let bits_perserve = (operand.size() as u64 - value) * 8;    // Convert bytes to throw away to bits to perserve
+                    let bits_result = result.size() as u64 *8;
+                    let bits_smaller = std::cmp::min(bits_perserve, bits_result);
+                    // The amount counts the least significant bytes to drop, whatever the byte order
+                    let lsb = (value * 8) as u32;
+                    let mut result_sym = Expr::extract(op_sym, lsb, lsb + bits_smaller as u32);
+                    if bits_smaller < bits_result {
+                        result_sym = Expr::zero_extend(result_sym, bits_result as u32);
+                    }
+                    self.var_list_insert(&result, Some(result_sym))?;
+                } else {
+                    let pc = state.program_counter_value().map(u64::from).unwrap_or(0);
+                    return Err(SolverError::UnsupportedOperation { pc, operation: format!("{:?}", instruction) });
+                }
+            },
+            ////////////////////////////////////////////////
+            // Logical Operation
+            PCodeOp::IntEq{result, operands} => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+
+                let result_sym = Expr::binary(BinOp::Eq, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::IntNotEq { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+
+                let result_sym = Expr::binary(BinOp::Ne, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::IntSLess { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+
+                let result_sym = Expr::binary(BinOp::Slt, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+
+            },
+            PCodeOp::IntLess { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+
+                let result_sym = Expr::binary(BinOp::Lt, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+
+            },
+            ////////////////////////////////////////////////
+            // Arithmetic
+            PCodeOp::IntSub { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+
+                // works for both signed and unsigned
+                let result_sym = Expr::binary(BinOp::Sub, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+
+            },
+            PCodeOp::IntAdd { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+                // Works for both signed and unsigned
+                let result_sym = Expr::binary(BinOp::Add, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::IntNeg { result, operand } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operand)?;
+                let result_sym = Expr::unary(UnOp::Neg, op1_sym);
+
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::IntCarry { result, operands } =>{
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+                // Works for both signed and unsigned
+                let result_sym = Expr::binary(BinOp::Carry, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::IntSCarry { result, operands } =>{
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+                // Works for both signed and unsigned
+                let result_sym = Expr::binary(BinOp::SCarry, op1_sym, op2_sym);
+
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+            },
+            PCodeOp::BoolOr { result, operands } => {
+                let op1_sym = self.symexpr_from_operand_read(state, &operands[0])?;
+                let op2_sym = self.symexpr_from_operand_read(state, &operands[1])?;
+                // Works for both signed and unsigned
+                let result_sym = Expr::binary(BinOp::Or, op1_sym, op2_sym);
+                // insert result
+                self.var_list_insert(&result, Some(result_sym))?;
+            }
+            PCodeOp::Skip => (),
+
+            _ => {
+                let pc = state.program_counter_value().map(u64::from).unwrap_or(0);
+                return Err(SolverError::UnsupportedOperation { pc, operation: format!("{:?}", instruction) });
+            }
+        }
+        Ok(())
+    }
+
+
+    /// Add the next operation of the region without modelling it, e.g. inside a callee doing
+    /// unsupported work: its output takes the concrete value it gets once the operation is
+    /// executed, and loses any dependence on the inputs
+    /// state: state before the operation is executed
+    pub fn add_pcode_concrete(&mut self, instruction: &PCodeOp, state: &PCodeState<StateValueType, O>) -> Result<(), SolverError>{
+        if !self.started {
+            return Err(SolverError::NotStarted);
+        }
+        match instruction {
+            PCodeOp::Store { source, destination, space: _ } => {
+                let address = state.get_address(destination)
+                    .map_err(|e| SolverError::State(format!("{:?}", e)))?;
+                self.forget_memory(u64::from(address), source.size());
+            },
+            PCodeOp::Branch { .. } | PCodeOp::CBranch { .. } | PCodeOp::IBranch { .. }
+            | PCodeOp::Call { .. } | PCodeOp::ICall { .. } | PCodeOp::Return { .. }
+            | PCodeOp::Skip => (),
+            _ => match decompose(instruction) {
+                (_, _, Some(output)) => self.store.invalidate(output)?,
+                // Unknown output: assume it may be any register
+                (_, _, None) => self.store.invalidate_registers(),
+            },
+        }
+        Ok(())
+    }
+
+    /// The query making `operand` equal to `expected_value`, over the inputs of the region
+    /// operand: the operand to be solved, e.g. the condition of a CBranch
+    pub fn query(&mut self, state: &PCodeState<StateValueType, O>, operand: &Operand, expected_value: u64) -> Result<Query, SolverError>{
+        if !self.started {
+            return Err(SolverError::NotStarted);
+        }
+        if self.var_to_solve.is_empty() {
+            return Err(SolverError::NoSymbolicInput);
+        }
+
+        // Get the expression of the operand
+        let condition = self.symexpr_from_operand_read(state, operand)?;
+        let original_nodes = Expr::node_count(&[&condition]);
+        let condition = if self.simplify { simplify(&condition) } else { condition };
+
+        // The vars in var_to_solve list, with the address of their register
+        let mut inputs: Vec<(Expr, Address)> = self.var_to_solve.values().cloned().collect();
+        inputs.sort_by_key(|(_, address)| *address);
+
+        let query = Query { condition, expected: expected_value, inputs, assumptions: Vec::new(), original_nodes };
+        log::debug!("Query of {} nodes, {} before simplification ({:.1}% smaller)",
+            query.node_count(), original_nodes, query.shrink_percent());
+        Ok(query)
+    }
+
+    /// Solve for the inputs making `operand` equal to `expected_value`
+    /// operand: the operand to be solved, e.g. the condition of a CBranch
+    /// The query is abandoned with `SolveOutcome::Unknown` when it exceeds the SolverLimits
+    pub fn solve(&mut self, state: &PCodeState<StateValueType, O>, operand: &Operand, expected_value: u64) -> Result<SolveOutcome, SolverError>{
+        let query = self.query(state, operand, expected_value)?;
+        self.solve_query(&query)
+    }
+
+    /// Solve a query made by `query`
+    /// Queries made before are answered from the cache, if enabled
+    pub fn solve_query(&self, query: &Query) -> Result<SolveOutcome, SolverError>{
+        let key = match &self.cache {
+            Some(cache) => match cache.lock().lookup(query) {
+                Ok(outcome) => {
+                    log::debug!("Solver: query answered from the cache");
+                    return Ok(outcome);
+                },
+                Err(key) => Some(key),
+            },
+            None => None,
+        };
+        let outcome = self.solve_uncached(query)?;
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            cache.lock().insert(key, outcome.clone());
+        }
+        Ok(outcome)
+    }
+
+    fn solve_uncached(&self, query: &Query) -> Result<SolveOutcome, SolverError>{
+        let constraints = match self.constraints(query) {
+            Ok(constraints) => constraints,
+            Err(limit) => return Ok(SolveOutcome::Unknown(limit)),
+        };
+        let inputs = query.inputs.clone();
+        Ok(match self.run(move || run_query(&constraints, &inputs))? {
+            Ok(result) => SolveOutcome::Solved(result),
+            Err(limit) => SolveOutcome::Unknown(limit),
+        })
+    }
+
+    /// Up to `max_values` distinct values of the input loaded from `address` satisfying the query
+    /// Each value found is blocked to find the next one
+    /// Values asked before are answered from the cache, if enabled
+    pub fn enumerate(&self, query: &Query, address: &Address, max_values: usize) -> Result<ValuesOutcome, SolverError>{
+        self.cached_values(query, address, Selection::Enumerate(max_values), || {
+            let input = query.input(address)?.clone();
+            let constraints = match self.constraints(query) {
+                Ok(constraints) => constraints,
+                Err(limit) => return Ok(ValuesOutcome::Unknown(limit)),
+            };
+            Ok(match self.run(move || enumerate_values(&constraints, &input, max_values))? {
+                Ok(values) => ValuesOutcome::Values(values),
+                Err(limit) => ValuesOutcome::Unknown(limit),
+            })
+        })
+    }
+
+    /// Unsigned minimum or maximum of the input loaded from `address` satisfying the query
+    /// Found by binary search over the value, for inputs of at most 64 bits
+    /// Values asked before are answered from the cache, if enabled
+    pub fn optimize(&self, query: &Query, address: &Address, objective: Objective) -> Result<ValuesOutcome, SolverError>{
+        self.cached_values(query, address, Selection::Optimize(objective), || {
+            let input = query.input(address)?.clone();
+            if input.bits() > 64 {
+                return Err(SolverError::UnsupportedSize(input.bits() as usize / 8));
+            }
+            let constraints = match self.constraints(query) {
+                Ok(constraints) => constraints,
+                Err(limit) => return Ok(ValuesOutcome::Unknown(limit)),
+            };
+            Ok(match self.run(move || optimize_value(&constraints, &input, objective))? {
+                Ok(value) => ValuesOutcome::Values(value.into_iter().collect()),
+                Err(limit) => ValuesOutcome::Unknown(limit),
+            })
+        })
+    }
+
+    // Values of an input from the cache, or from `solve` and then cached
+    fn cached_values<F>(&self, query: &Query, address: &Address, selection: Selection, solve: F) -> Result<ValuesOutcome, SolverError>
+    where
+        F: FnOnce() -> Result<ValuesOutcome, SolverError>,
+    {
+        let key = match &self.cache {
+            Some(cache) => match cache.lock().lookup_values(query, address, selection) {
+                Ok(outcome) => {
+                    log::debug!("Solver: values of {} answered from the cache", address);
+                    return Ok(outcome);
+                },
+                Err(key) => Some(key),
+            },
+            None => None,
+        };
+        let outcome = solve()?;
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            cache.lock().insert_values(key, outcome.clone());
+        }
+        Ok(outcome)
+    }
+
+    // Constraints of the query: the condition equal to the expected value, and the assumptions
+    fn constraints(&self, query: &Query) -> Result<Vec<Expr>, LimitExceeded> {
+        if let Some(max_nodes) = self.limits.max_nodes {
+            let nodes = query.node_count();
+            if nodes > max_nodes {
+                log::warn!("Solver: query of {} nodes over the limit of {}", nodes, max_nodes);
+                return Err(LimitExceeded::ExpressionSize(nodes));
+            }
+        }
+
+        // Add constraint that the expected value is equal to the operand
+        let expected = Expr::val_u64(query.expected, query.condition.bits());
+        let mut constraints = vec![Expr::binary(BinOp::Eq, query.condition.clone(), expected)];
+        constraints.extend(query.assumptions.iter().cloned());
+        if self.simplify {
+            constraints = constraints.iter().map(simplify).collect();
+        }
+        Ok(constraints)
+    }
+
+    // Run the work within the time and memory budgets
+    fn run<T, F>(&self, work: F) -> Result<Result<T, LimitExceeded>, SolverError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        run_with_limits(work, self.limits)
+    }
+}
+
+// Solve the inputs under the constraints
+fn run_query(constraints: &[Expr], inputs: &[(Expr, Address)]) -> SolveResult {
+    let mut lowering = Lowering::new();
+    let constraints: Vec<_> = constraints.iter().map(|constraint| lowering.lower(constraint)).collect();
+    let mut solver_context = SolverContext::new_independent();
+
+    // The solving results to be returned: a list of (address, value)
+    let mut return_res = SolveResult::new();
+    for (input, addr) in inputs {
+        let solve_res = lowering.lower(input).solve(&mut solver_context, &constraints);
+        if solve_res.is_none() {
+            // solution not found for the variable
+            log::warn!("Solver: No solution found for variable {}", addr);
+        }
+        return_res.insert(*addr, solve_res);
+    }
+    return_res
+}
+
+// Distinct values of the input, blocking each value found
+fn enumerate_values(constraints: &[Expr], input: &Expr, max_values: usize) -> Vec<BitVec> {
+    let mut lowering = Lowering::new();
+    let mut lowered: Vec<_> = constraints.iter().map(|constraint| lowering.lower(constraint)).collect();
+    let input_sym = lowering.lower(input);
+    let mut solver_context = SolverContext::new_independent();
+
+    // Blocking clauses are kept alive: the lowering is keyed by node
+    let mut blocking = Vec::new();
+    let mut values = Vec::new();
+    while values.len() < max_values {
+        let value = match input_sym.solve(&mut solver_context, &lowered) {
+            Some(value) => value,
+            None => break,
+        };
+        let clause = Expr::binary(BinOp::Ne, input.clone(), Expr::val(value.clone()));
+        lowered.push(lowering.lower(&clause));
+        blocking.push(clause);
+        values.push(value);
+    }
+    values
+}
+
+// Minimum or maximum value of the input, None if the constraints have no solution
+fn optimize_value(constraints: &[Expr], input: &Expr, objective: Objective) -> Option<BitVec> {
+    let bits = input.bits();
+    let mut lowering = Lowering::new();
+    let lowered: Vec<_> = constraints.iter().map(|constraint| lowering.lower(constraint)).collect();
+    let input_sym = lowering.lower(input);
+    let mut solver_context = SolverContext::new_independent();
+
+    // Bounds are kept alive: the lowering is keyed by node
+    let mut bounds = Vec::new();
+    let mut solve_bounded = |bound: Option<Expr>| {
+        let mut constraints = lowered.clone();
+        if let Some(bound) = bound {
+            constraints.push(lowering.lower(&bound));
+            bounds.push(bound);
+        }
+        input_sym.solve(&mut solver_context, &constraints).and_then(|value| value.to_u64())
+    };
+
+    let first = solve_bounded(None)?;
+    let value = match objective {
+        Objective::Minimize => {
+            // The minimum is in [low, high], high being satisfiable
+            let (mut low, mut high) = (0u64, first);
+            while low < high {
+                let mid = low + (high - low) / 2;
+                match solve_bounded(Some(Expr::binary(BinOp::Lt, input.clone(), Expr::val_u64(mid + 1, bits)))) {
+                    Some(value) => high = value,
+                    None => low = mid + 1,
+                }
+            }
+            high
+        },
+        Objective::Maximize => {
+            // The maximum is in [low, high], low being satisfiable
+            let max = if bits >= 64 { u64::MAX } else { (1u64 << bits) - 1 };
+            let (mut low, mut high) = (first, max);
+            while low < high {
+                let mid = low + (high - low - 1) / 2 + 1;
+                match solve_bounded(Some(Expr::binary(BinOp::Lt, Expr::val_u64(mid - 1, bits), input.clone()))) {
+                    Some(value) => low = value,
+                    None => high = mid - 1,
+                }
+            }
+            low
+        },
+    };
+    Some(BitVec::from_u64(value, bits as usize))
+}
+
+// Run on a thread of its own, with a stack large enough for deep expressions,
+// so the budgets can be checked while Boolector runs
+// Boolector cannot be interrupted: work over budget keeps running in the background
+// until it returns, and its result is dropped
+fn run_with_limits<T, F>(work: F, limits: SolverLimits) -> Result<Result<T, LimitExceeded>, SolverError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let limited = limits.timeout.is_some() || limits.max_memory.is_some();
+    let running = abandoned_queries();
+    if limited && running >= MAX_ABANDONED_QUERIES {
+        log::warn!("Solver: {} abandoned queries still running, query not started", running);
+        return Ok(Err(LimitExceeded::AbandonedQueries(running)));
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    // Set by whichever of the thread finishing and the query being abandoned comes first
+    let finished = Arc::new(AtomicBool::new(false));
+    let thread_finished = finished.clone();
+    let memory_before = resident_memory();
+    thread::Builder::new()
+        .name("constraint-solver".to_string())
+        .stack_size(QUERY_STACK_SIZE)
+        .spawn(move || {
+            let _finished = FinishedGuard(thread_finished);
+            // The receiver is gone if the query went over budget
+            let _ = sender.send(work());
+        })
+        .map_err(|e| SolverError::Query(e.to_string()))?;
+
+    if !limited {
+        return receiver.recv()
+            .map(Ok)
+            .map_err(|_| SolverError::Query("the solver thread panicked".to_string()));
+    }
+
+    // Count the thread as abandoned, unless it has finished in the meantime
+    let abandon = || {
+        ABANDONED_QUERIES.fetch_add(1, Ordering::AcqRel);
+        if finished.swap(true, Ordering::AcqRel) {
+            ABANDONED_QUERIES.fetch_sub(1, Ordering::AcqRel);
+        }
+    };
+    let start = Instant::now();
+    loop {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(result) => return Ok(Ok(result)),
+            Err(RecvTimeoutError::Disconnected) => return Err(SolverError::Query("the solver thread panicked".to_string())),
+            Err(RecvTimeoutError::Timeout) => (),
+        }
+        if let Some(timeout) = limits.timeout {
+            if start.elapsed() >= timeout {
+                log::warn!("Solver: query timed out after {:?}", timeout);
+                abandon();
+                return Ok(Err(LimitExceeded::Time(timeout)));
+            }
+        }
+        // The memory allocated by abandoned queries would be charged to this one
+        if abandoned_queries() > 0 {
+            continue;
+        }
+        if let (Some(max_memory), Some(before), Some(now)) = (limits.max_memory, memory_before, resident_memory()) {
+            let used = now.saturating_sub(before);
+            if used > max_memory {
+                log::warn!("Solver: query used {} bytes, over the limit of {}", used, max_memory);
+                abandon();
+                return Ok(Err(LimitExceeded::Memory(used)));
+            }
+        }
+    }
+}
+
+// Marks the solver thread as finished when it returns or panics
+struct FinishedGuard(Arc<AtomicBool>);
+
+impl Drop for FinishedGuard {
+    fn drop(&mut self) {
+        // The query was abandoned first: it is no longer running
+        if self.0.swap(true, Ordering::AcqRel) {
+            ABANDONED_QUERIES.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+// Resident memory of the process in bytes, None where /proc is not available
+fn resident_memory() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
@@ -0,0 +1,267 @@
+//! Simplification of queries before solving
+//!
+//! Expressions are built one-to-one from P-code, so they carry the copies,
+//! extensions, subpieces and flag computations of the code. The pass rewrites
+//! them bottom-up:
+//!
+//! - constant folding, for values of at most 64 bits;
+//! - extends and extracts: identity extracts and extends are dropped,
+//!   extracts go through extends and concatenations, adjacent extracts of the
+//!   same value are merged back;
+//! - identities: `x & 0`, `x | 0`, `x + 0`, `x ^ x`, `x == x`, `ite(c, x, x)`...;
+//! - booleans: comparisons of a boolean with 0 or 1 are normalised to the
+//!   boolean or its negation (`(a == b) == 0` is `a != b`).
+//!
+//! Flag computations not reaching the condition are not part of a query; those
+//! left dead by folding (e.g. the branch of an `ite` on a constant) are dropped
+//! with it.
+use std::collections::HashMap;
+
+use super::expr::{BinOp, Expr, Node, UnOp};
+
+fn mask(bits: u32) -> u64 {
+    if bits >= 64 { u64::MAX } else { (1u64 << bits) - 1 }
+}
+
+fn signed(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits.min(64);
+    ((value << shift) as i64) >> shift
+}
+
+// Value of a constant of at most 64 bits
+fn constant(expr: &Expr) -> Option<u64> {
+    match expr.node() {
+        Node::Val(value) if value.bits() <= 64 => value.to_u64(),
+        _ => None,
+    }
+}
+
+fn val(value: u64, bits: u32) -> Expr {
+    Expr::val_u64(value & mask(bits), bits)
+}
+
+fn boolean(value: bool) -> Expr {
+    val(value as u64, 8)
+}
+
+fn is_boolean(expr: &Expr) -> bool {
+    matches!(expr.node(), Node::Binary(op, _, _) if op.is_boolean())
+}
+
+// Negation of a comparison, if it has one without a new operation
+fn negate(expr: &Expr) -> Option<Expr> {
+    match expr.node() {
+        Node::Binary(BinOp::Eq, lhs, rhs) => Some(Expr::binary(BinOp::Ne, lhs.clone(), rhs.clone())),
+        Node::Binary(BinOp::Ne, lhs, rhs) => Some(Expr::binary(BinOp::Eq, lhs.clone(), rhs.clone())),
+        _ => None,
+    }
+}
+
+fn fold_binary(op: BinOp, a: u64, b: u64, bits: u32) -> Expr {
+    let m = mask(bits);
+    match op {
+        BinOp::And | BinOp::BoolAnd => val(a & b, bits),
+        BinOp::Or => val(a | b, bits),
+        BinOp::Xor | BinOp::BoolXor => val(a ^ b, bits),
+        BinOp::Add => val(a.wrapping_add(b), bits),
+        BinOp::Sub => val(a.wrapping_sub(b), bits),
+        BinOp::Shl => val(if b >= bits as u64 { 0 } else { a << b }, bits),
+        BinOp::Shr => val(if b >= bits as u64 { 0 } else { a >> b }, bits),
+        BinOp::Sar => {
+            let shifted = signed(a, bits) >> b.min(63);
+            val(shifted as u64, bits)
+        },
+        BinOp::Eq => boolean(a == b),
+        BinOp::Ne => boolean(a != b),
+        BinOp::Lt => boolean(a < b),
+        BinOp::Slt => boolean(signed(a, bits) < signed(b, bits)),
+        BinOp::Carry => boolean(a as u128 + b as u128 > m as u128),
+        BinOp::SCarry => {
+            let sum = signed(a, bits) as i128 + signed(b, bits) as i128;
+            let (min, max) = (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1);
+            boolean(sum < min || sum > max)
+        },
+    }
+}
+
+// Rewrite a node whose children are simplified
+fn rewrite(node: Node) -> Expr {
+    match node {
+        Node::Unary(UnOp::Neg, operand) => match constant(&operand) {
+            Some(a) => val(a.wrapping_neg(), operand.bits()),
+            None => Expr::unary(UnOp::Neg, operand),
+        },
+        Node::Binary(op, lhs, rhs) => binary(op, lhs, rhs),
+        Node::Extract { expr, lsb, msb } => extract(expr, lsb, msb),
+        Node::Concat(high, low) => concat(high, low),
+        Node::ZeroExtend(operand, bits) => {
+            if operand.bits() == bits {
+                return operand;
+            }
+            if let Some(a) = constant(&operand) {
+                if bits <= 64 {
+                    return val(a, bits);
+                }
+            }
+            match operand.node() {
+                Node::ZeroExtend(inner, _) => Expr::zero_extend(inner.clone(), bits),
+                _ => Expr::zero_extend(operand, bits),
+            }
+        },
+        Node::SignExtend(operand, bits) => {
+            if operand.bits() == bits {
+                return operand;
+            }
+            if let Some(a) = constant(&operand) {
+                if bits <= 64 {
+                    return val(signed(a, operand.bits()) as u64, bits);
+                }
+            }
+            match operand.node() {
+                Node::SignExtend(inner, _) => Expr::sign_extend(inner.clone(), bits),
+                Node::ZeroExtend(inner, _) => Expr::zero_extend(inner.clone(), bits),
+                _ => Expr::sign_extend(operand, bits),
+            }
+        },
+        Node::Ite(condition, then, otherwise) => {
+            if let Some(c) = constant(&condition) {
+                return if c != 0 { then } else { otherwise };
+            }
+            if then == otherwise {
+                return then;
+            }
+            Expr::ite(condition, then, otherwise)
+        },
+        node => Expr::new(node),
+    }
+}
+
+fn binary(op: BinOp, lhs: Expr, rhs: Expr) -> Expr {
+    let bits = lhs.bits();
+    let (a, b) = (constant(&lhs), constant(&rhs));
+    if let (Some(a), Some(b)) = (a, b) {
+        return fold_binary(op, a, b, bits);
+    }
+    // Constants on the right of commutative operations
+    let commutative = matches!(op, BinOp::And | BinOp::Or | BinOp::Xor | BinOp::BoolAnd | BinOp::BoolXor
+        | BinOp::Add | BinOp::Eq | BinOp::Ne);
+    let (lhs, rhs, b) = if commutative && a.is_some() { (rhs, lhs, a) } else { (lhs, rhs, b) };
+
+    match (op, b) {
+        (BinOp::And, Some(0)) | (BinOp::BoolAnd, Some(0)) => return val(0, bits),
+        (BinOp::And, Some(b)) if b == mask(bits) => return lhs,
+        (BinOp::BoolAnd, Some(1)) if is_boolean(&lhs) => return lhs,
+        (BinOp::Or, Some(0)) | (BinOp::Xor, Some(0)) | (BinOp::BoolXor, Some(0))
+        | (BinOp::Add, Some(0)) | (BinOp::Sub, Some(0))
+        | (BinOp::Shl, Some(0)) | (BinOp::Shr, Some(0)) | (BinOp::Sar, Some(0)) => return lhs,
+        (BinOp::Or, Some(b)) if b == mask(bits) => return val(b, bits),
+        (BinOp::BoolXor, Some(1)) => if let Some(negated) = negate(&lhs) { return negated },
+        // Boolean normalisation
+        (BinOp::Eq, Some(1)) | (BinOp::Ne, Some(0)) if is_boolean(&lhs) => return lhs,
+        (BinOp::Eq, Some(0)) | (BinOp::Ne, Some(1)) if is_boolean(&lhs) => {
+            if let Some(negated) = negate(&lhs) {
+                return negated;
+            }
+        },
+        (BinOp::Lt, Some(0)) => return boolean(false),
+        _ => (),
+    }
+
+    if lhs == rhs {
+        match op {
+            BinOp::And | BinOp::Or | BinOp::BoolAnd => return lhs,
+            BinOp::Xor | BinOp::BoolXor | BinOp::Sub => return val(0, bits),
+            BinOp::Eq => return boolean(true),
+            BinOp::Ne | BinOp::Lt | BinOp::Slt => return boolean(false),
+            _ => (),
+        }
+    }
+    Expr::binary(op, lhs, rhs)
+}
+
+fn extract(expr: Expr, lsb: u32, msb: u32) -> Expr {
+    let bits = msb - lsb;
+    if lsb == 0 && msb == expr.bits() {
+        return expr;
+    }
+    if let Some(a) = constant(&expr) {
+        return val(a >> lsb, bits);
+    }
+    match expr.node() {
+        Node::Extract { expr: inner, lsb: inner_lsb, .. } => {
+            extract(inner.clone(), inner_lsb + lsb, inner_lsb + msb)
+        },
+        Node::ZeroExtend(inner, _) | Node::SignExtend(inner, _) if msb <= inner.bits() => {
+            extract(inner.clone(), lsb, msb)
+        },
+        Node::ZeroExtend(inner, _) if lsb >= inner.bits() => val(0, bits),
+        Node::Concat(high, low) => {
+            let low_bits = low.bits();
+            if msb <= low_bits {
+                extract(low.clone(), lsb, msb)
+            } else if lsb >= low_bits {
+                extract(high.clone(), lsb - low_bits, msb - low_bits)
+            } else {
+                Expr::extract(expr, lsb, msb)
+            }
+        },
+        _ => Expr::extract(expr, lsb, msb),
+    }
+}
+
+fn concat(high: Expr, low: Expr) -> Expr {
+    let low_bits = low.bits();
+    if let (Some(h), Some(l)) = (constant(&high), constant(&low)) {
+        if high.bits() + low_bits <= 64 {
+            return val((h << low_bits) | l, high.bits() + low_bits);
+        }
+    }
+    if constant(&high) == Some(0) {
+        return Expr::zero_extend(low, high.bits() + low_bits);
+    }
+    // Adjacent parts of the same value, as read back from the bytes of a varnode
+    if let (Node::Extract { expr: h, lsb: h_lsb, msb: h_msb }, Node::Extract { expr: l, lsb: l_lsb, msb: l_msb }) = (high.node(), low.node()) {
+        if h == l && *h_lsb == *l_msb {
+            return extract(h.clone(), *l_lsb, *h_msb);
+        }
+    }
+    Expr::concat(high, low)
+}
+
+/// Simplify an expression, shared sub-expressions are simplified once
+pub fn simplify(root: &Expr) -> Expr {
+    let mut done: HashMap<*const Node, Expr> = HashMap::new();
+    // Post-order, without recursion: conditions can be long chains
+    let mut stack = vec![(root.clone(), false)];
+    while let Some((expr, expanded)) = stack.pop() {
+        if done.contains_key(&expr.id()) {
+            continue;
+        }
+        if !expanded {
+            stack.push((expr.clone(), true));
+            for child in expr.children() {
+                if !done.contains_key(&child.id()) {
+                    stack.push((child.clone(), false));
+                }
+            }
+            continue;
+        }
+        if expr.children().is_empty() {
+            done.insert(expr.id(), expr.clone());
+            continue;
+        }
+        let simplified = |child: &Expr| done[&child.id()].clone();
+        let node = match expr.node() {
+            Node::Val(_) | Node::Var { .. } => unreachable!(),
+            Node::Unary(op, operand) => Node::Unary(*op, simplified(operand)),
+            Node::Binary(op, lhs, rhs) => Node::Binary(*op, simplified(lhs), simplified(rhs)),
+            Node::Extract { expr: operand, lsb, msb } => Node::Extract { expr: simplified(operand), lsb: *lsb, msb: *msb },
+            Node::Concat(high, low) => Node::Concat(simplified(high), simplified(low)),
+            Node::ZeroExtend(operand, bits) => Node::ZeroExtend(simplified(operand), *bits),
+            Node::SignExtend(operand, bits) => Node::SignExtend(simplified(operand), *bits),
+            Node::Ite(condition, then, otherwise) => Node::Ite(simplified(condition), simplified(then), simplified(otherwise)),
+        };
+        done.insert(expr.id(), rewrite(node));
+    }
+    done[&root.id()].clone()
+}
@@ -0,0 +1,202 @@
+//! SMT-LIB2 export of queries
+//!
+//! A query is written as a QF_BV problem: one constant per variable, the
+//! condition as a function (sub-expressions shared by several nodes are
+//! defined once), the assertion that it equals the expected value, the
+//! assumptions, and the inputs to get the value of. The output can be replayed with any SMT-LIB2
+//! solver, e.g. `z3 query.smt2` or `boolector query.smt2`.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Write};
+use fugue::bv::BitVec;
+use fugue::bytes::BE;
+
+use super::Query;
+use super::expr::{BinOp, Expr, Node, UnOp};
+
+const TRUE: &str = "#x01";
+const FALSE: &str = "#x00";
+
+struct Printer {
+    parents: HashMap<*const Node, usize>,   // Number of nodes using each node
+    names: HashMap<*const Node, String>,    // Shared nodes already defined
+    definitions: String,
+}
+
+impl Printer {
+    fn new(roots: &[&Expr]) -> Self {
+        let mut parents: HashMap<*const Node, usize> = HashMap::new();
+        let mut stack: Vec<&Expr> = roots.to_vec();
+        while let Some(expr) = stack.pop() {
+            let count = parents.entry(expr.id()).or_insert(0);
+            *count += 1;
+            if *count == 1 {
+                stack.extend(expr.children());
+            }
+        }
+        Self { parents, names: HashMap::new(), definitions: String::new() }
+    }
+
+    // Term of the expression, shared non-leaf nodes are defined once and referenced
+    fn term(&mut self, expr: &Expr) -> Result<String, fmt::Error> {
+        if let Some(name) = self.names.get(&expr.id()) {
+            return Ok(name.clone());
+        }
+        let term = self.node(expr)?;
+        let shared = self.parents.get(&expr.id()).copied().unwrap_or(0) > 1;
+        if !shared || expr.children().is_empty() {
+            return Ok(term);
+        }
+        let name = format!("e{}", self.names.len());
+        writeln!(self.definitions, "(define-fun {} () {} {})", name, sort(expr.bits()), term)?;
+        self.names.insert(expr.id(), name.clone());
+        Ok(name)
+    }
+
+    fn node(&mut self, expr: &Expr) -> Result<String, fmt::Error> {
+        Ok(match expr.node() {
+            Node::Val(value) => literal(value),
+            Node::Var { name, .. } => symbol(name),
+            Node::Unary(UnOp::Neg, operand) => format!("(bvneg {})", self.term(operand)?),
+            Node::Binary(op, lhs, rhs) => {
+                let (lhs_bits, rhs_bits) = (lhs.bits(), rhs.bits());
+                let (lhs, rhs) = (self.term(lhs)?, self.term(rhs)?);
+                binary(*op, &lhs, lhs_bits, &rhs, rhs_bits)
+            },
+            Node::Extract { expr, lsb, msb } => format!("((_ extract {} {}) {})", msb - 1, lsb, self.term(expr)?),
+            Node::Concat(high, low) => format!("(concat {} {})", self.term(high)?, self.term(low)?),
+            Node::ZeroExtend(operand, bits) => extend("zero_extend", &self.term(operand)?, operand.bits(), *bits),
+            Node::SignExtend(operand, bits) => extend("sign_extend", &self.term(operand)?, operand.bits(), *bits),
+            Node::Ite(condition, then, otherwise) => {
+                let zero = literal(&BitVec::from_u64(0, condition.bits() as usize));
+                format!("(ite (distinct {} {}) {} {})", self.term(condition)?, zero, self.term(then)?, self.term(otherwise)?)
+            },
+        })
+    }
+}
+
+fn sort(bits: u32) -> String {
+    format!("(_ BitVec {})", bits)
+}
+
+// Quoted symbol, variable names contain characters such as ':'
+fn symbol(name: &str) -> String {
+    format!("|{}|", name.replace(|c| c == '|' || c == '\\', "_"))
+}
+
+fn literal(value: &BitVec) -> String {
+    let bits = value.bits();
+    let mut bytes = vec![0u8; (bits + 7) / 8];
+    value.clone().into_bytes::<BE>(&mut bytes);
+    if bits % 4 == 0 {
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("#x{}", &hex[hex.len() - bits / 4..])
+    } else {
+        let bin: String = bytes.iter().map(|b| format!("{:08b}", b)).collect();
+        format!("#b{}", &bin[bin.len() - bits..])
+    }
+}
+
+fn extend(kind: &str, term: &str, from: u32, to: u32) -> String {
+    if to > from {
+        format!("((_ {} {}) {})", kind, to - from, term)
+    } else {
+        term.to_string()
+    }
+}
+
+fn boolean(term: String) -> String {
+    format!("(ite {} {} {})", term, TRUE, FALSE)
+}
+
+fn binary(op: BinOp, lhs: &str, lhs_bits: u32, rhs: &str, rhs_bits: u32) -> String {
+    match op {
+        BinOp::And | BinOp::BoolAnd => format!("(bvand {} {})", lhs, rhs),
+        BinOp::Or => format!("(bvor {} {})", lhs, rhs),
+        BinOp::Xor | BinOp::BoolXor => format!("(bvxor {} {})", lhs, rhs),
+        BinOp::Add => format!("(bvadd {} {})", lhs, rhs),
+        BinOp::Sub => format!("(bvsub {} {})", lhs, rhs),
+        BinOp::Shl | BinOp::Shr | BinOp::Sar => {
+            let (function, extension) = match op {
+                BinOp::Shl => ("bvshl", "zero_extend"),
+                BinOp::Shr => ("bvlshr", "zero_extend"),
+                _ => ("bvashr", "sign_extend"),
+            };
+            // P-code shift amounts may be of another size than the value shifted
+            if rhs_bits <= lhs_bits {
+                format!("({} {} {})", function, lhs, extend("zero_extend", rhs, rhs_bits, lhs_bits))
+            } else {
+                let shifted = format!("({} {} {})", function, extend(extension, lhs, lhs_bits, rhs_bits), rhs);
+                format!("((_ extract {} 0) {})", lhs_bits - 1, shifted)
+            }
+        },
+        BinOp::Eq => boolean(format!("(= {} {})", lhs, rhs)),
+        BinOp::Ne => boolean(format!("(distinct {} {})", lhs, rhs)),
+        BinOp::Lt => boolean(format!("(bvult {} {})", lhs, rhs)),
+        BinOp::Slt => boolean(format!("(bvslt {} {})", lhs, rhs)),
+        BinOp::Carry => boolean(format!("(bvult (bvadd {} {}) {})", lhs, rhs, lhs)),
+        BinOp::SCarry => {
+            let sign = |term: &str| format!("((_ extract {} {}) {})", lhs_bits - 1, lhs_bits - 1, term);
+            let sum = format!("(bvadd {} {})", lhs, rhs);
+            boolean(format!("(and (= {} {}) (distinct {} {}))", sign(lhs), sign(rhs), sign(lhs), sign(&sum)))
+        },
+    }
+}
+
+// Variables of the expressions, by name
+fn variables<'a>(roots: &[&'a Expr]) -> BTreeMap<&'a str, u32> {
+    let mut variables = BTreeMap::new();
+    let mut seen = HashSet::new();
+    let mut stack: Vec<&Expr> = roots.to_vec();
+    while let Some(expr) = stack.pop() {
+        if !seen.insert(expr.id()) {
+            continue;
+        }
+        if let Node::Var { name, bits } = expr.node() {
+            variables.insert(name.as_str(), *bits);
+        }
+        stack.extend(expr.children());
+    }
+    variables
+}
+
+/// Write the query as an SMT-LIB2 script
+pub fn write_query<W: Write>(query: &Query, writer: &mut W) -> fmt::Result {
+    let mut roots = vec![&query.condition];
+    roots.extend(query.inputs.iter().map(|(input, _)| input));
+    roots.extend(query.assumptions.iter());
+
+    writeln!(writer, "; fuguex peripheral query")?;
+    writeln!(writer, "; {} nodes, {} before simplification", query.node_count(), query.original_nodes)?;
+    for (input, address) in &query.inputs {
+        if let Node::Var { name, .. } = input.node() {
+            writeln!(writer, "; input {} loaded from {}", symbol(name), address)?;
+        }
+    }
+    writeln!(writer, "(set-option :produce-models true)")?;
+    writeln!(writer, "(set-logic QF_BV)")?;
+    for (name, bits) in variables(&roots) {
+        writeln!(writer, "(declare-const {} {})", symbol(name), sort(bits))?;
+    }
+
+    let mut printer = Printer::new(&roots);
+    let condition = printer.term(&query.condition)?;
+    let assumptions = query.assumptions.iter()
+        .map(|assumption| printer.term(assumption).map(|term| (term, assumption.bits())))
+        .collect::<Result<Vec<_>, _>>()?;
+    writer.write_str(&printer.definitions)?;
+    writeln!(writer, "(define-fun condition () {} {})", sort(query.condition.bits()), condition)?;
+    let expected = BitVec::from_u64(query.expected, query.condition.bits() as usize);
+    writeln!(writer, "(assert (= condition {}))", literal(&expected))?;
+    for (assumption, bits) in assumptions {
+        writeln!(writer, "(assert (distinct {} {}))", assumption, literal(&BitVec::from_u64(0, bits as usize)))?;
+    }
+    writeln!(writer, "(check-sat)")?;
+    let inputs: Vec<String> = variables(&query.inputs.iter().map(|(input, _)| input).collect::<Vec<_>>())
+        .keys()
+        .map(|name| symbol(name))
+        .collect();
+    if !inputs.is_empty() {
+        writeln!(writer, "(get-value ({}))", inputs.join(" "))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+//! Byte-level model of varnodes
+//!
+//! Registers, unique-space temporaries and memory are modelled byte by
+//! byte, keyed by (space, offset), so overlapping varnodes stay consistent:
+//! a write to `AL` is seen by a read of `EAX`, and a read of part of a
+//! varnode returns part of the value written to it. Each byte remembers the
+//! expression it was written from and its position in it; reads merge runs
+//! of bytes coming from the same expression into a single extract, and
+//! concatenate the runs.
+//!
+//! Bytes also carry whether they depend on a symbolic input, so concrete
+//! values (e.g. a pointer computed from constants) can be told apart from
+//! symbolic ones without looking into the expressions.
+use std::collections::HashMap;
+use fugue::ir::il::pcode::Operand;
+
+use super::SolverError;
+use super::expr::Expr;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Space {
+    Register,
+    Unique(String),
+    Memory,
+}
+
+// Byte of a varnode: bits [lsb, lsb + 8) of `source`
+#[derive(Debug, Clone)]
+struct ByteSlot {
+    source: Expr,
+    lsb: u32,
+    symbolic: bool,     // Depends on a symbolic input
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct VarnodeStore {
+    bytes: HashMap<(Space, u64), ByteSlot>,
+    versions: HashMap<String, u64>,     // Number of fresh variables created per name
+    big_endian: bool,                   // Byte order of the varnode spaces
+}
+
+impl VarnodeStore {
+    pub fn new(big_endian: bool) -> Self {
+        Self {
+            bytes: HashMap::new(),
+            versions: HashMap::new(),
+            big_endian,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.versions.clear();
+    }
+
+    /// Space, offset and size in bytes of a varnode
+    pub fn location(operand: &Operand) -> Result<(Space, u64, usize), SolverError> {
+        match operand {
+            Operand::Address { value, size } => Ok((Space::Memory, u64::from(*value), *size)),
+            Operand::Register { name: _, offset, size } => Ok((Space::Register, *offset, *size)),
+            Operand::Variable { space, offset, size } => Ok((Space::Unique(format!("{:?}", space)), *offset, *size)),
+            _ => Err(SolverError::ConstantOperand(format!("{}", operand))),
+        }
+    }
+
+    /// Name used for the fresh variables of a varnode
+    pub fn name(operand: &Operand) -> Result<String, SolverError> {
+        match operand {
+            Operand::Address { value, size: _ } => Ok(format!("{}", value)),
+            Operand::Register { name, offset: _, size: _ } => Ok(name.to_string()),
+            Operand::Variable { space, offset, size: _ } => Ok(format!("{:?}:{}", space, offset)),
+            _ => Err(SolverError::ConstantOperand(format!("{}", operand))),
+        }
+    }
+
+    /// New unconstrained variable for the varnode, `name`, then `name-1`, `name-2`...
+    pub fn fresh(&mut self, operand: &Operand) -> Result<Expr, SolverError> {
+        let name = Self::name(operand)?;
+        Ok(self.fresh_named(name, operand.size() as u32 * 8))
+    }
+
+    pub fn fresh_named(&mut self, name: String, bits: u32) -> Expr {
+        let version = self.versions.entry(name.clone()).or_insert(0);
+        let versioned = if *version == 0 { name } else { format!("{}-{}", name, version) };
+        *version += 1;
+        Expr::var(versioned, bits)
+    }
+
+    // Bit position in the value of the byte at offset + index
+    fn lsb(&self, index: usize, size: usize) -> u32 {
+        if self.big_endian {
+            ((size - 1 - index) * 8) as u32
+        } else {
+            (index * 8) as u32
+        }
+    }
+
+    fn write_bytes(&mut self, space: &Space, offset: u64, size: usize, expr: &Expr, symbolic: bool, only_missing: bool) {
+        for index in 0..size {
+            let key = (space.clone(), offset + index as u64);
+            if only_missing && self.bytes.contains_key(&key) {
+                continue;
+            }
+            let slot = ByteSlot { source: expr.clone(), lsb: self.lsb(index, size), symbolic };
+            self.bytes.insert(key, slot);
+        }
+    }
+
+    /// Write `expr` to the bytes of the varnode
+    pub fn write(&mut self, operand: &Operand, expr: &Expr, symbolic: bool) -> Result<(), SolverError> {
+        let (space, offset, size) = Self::location(operand)?;
+        self.write_bytes(&space, offset, size, expr, symbolic, false);
+        Ok(())
+    }
+
+    /// Write `expr` to the `size` bytes at `offset` in `space`
+    pub fn write_at(&mut self, space: &Space, offset: u64, size: usize, expr: &Expr, symbolic: bool) {
+        self.write_bytes(space, offset, size, expr, symbolic, false);
+    }
+
+    /// Write the bytes of `expr` to the bytes of the varnode never written to
+    pub fn fill(&mut self, operand: &Operand, expr: &Expr, symbolic: bool) -> Result<(), SolverError> {
+        let (space, offset, size) = Self::location(operand)?;
+        self.write_bytes(&space, offset, size, expr, symbolic, true);
+        Ok(())
+    }
+
+    pub fn fill_at(&mut self, space: &Space, offset: u64, size: usize, expr: &Expr, symbolic: bool) {
+        self.write_bytes(space, offset, size, expr, symbolic, true);
+    }
+
+    /// Forget the bytes of the varnode, they take their concrete value again
+    pub fn invalidate(&mut self, operand: &Operand) -> Result<(), SolverError> {
+        let (space, offset, size) = Self::location(operand)?;
+        self.invalidate_at(&space, offset, size);
+        Ok(())
+    }
+
+    pub fn invalidate_at(&mut self, space: &Space, offset: u64, size: usize) {
+        for index in 0..size as u64 {
+            self.bytes.remove(&(space.clone(), offset + index));
+        }
+    }
+
+    /// Forget every register and temporary, memory is kept
+    pub fn invalidate_registers(&mut self) {
+        self.bytes.retain(|(space, _), _| *space == Space::Memory);
+    }
+
+    /// Whether every, some or none of the bytes of the varnode were written to
+    pub fn is_known(&self, operand: &Operand) -> Result<Option<bool>, SolverError> {
+        let (space, offset, size) = Self::location(operand)?;
+        Ok(self.is_known_at(&space, offset, size))
+    }
+
+    pub fn is_known_at(&self, space: &Space, offset: u64, size: usize) -> Option<bool> {
+        let known = (0..size as u64).filter(|index| self.bytes.contains_key(&(space.clone(), offset + index))).count();
+        match known {
+            0 => Some(false),
+            known if known == size => Some(true),
+            _ => None,
+        }
+    }
+
+    /// Value of the varnode and whether it depends on a symbolic input,
+    /// None if one of its bytes was never written to
+    pub fn read(&self, operand: &Operand) -> Result<Option<(Expr, bool)>, SolverError> {
+        let (space, offset, size) = Self::location(operand)?;
+        Ok(self.read_at(&space, offset, size))
+    }
+
+    pub fn read_at(&self, space: &Space, offset: u64, size: usize) -> Option<(Expr, bool)> {
+        // Bytes from the most to the least significant
+        let mut slots = Vec::with_capacity(size);
+        for index in 0..size {
+            let slot = self.bytes.get(&(space.clone(), offset + index as u64))?;
+            slots.push((self.lsb(index, size), slot));
+        }
+        slots.sort_by(|a, b| b.0.cmp(&a.0));
+        let symbolic = slots.iter().any(|(_, slot)| slot.symbolic);
+
+        // Runs of bytes from the same expression, contiguous in it: (source, lsb, msb)
+        let mut runs: Vec<(&Expr, u32, u32)> = Vec::new();
+        for (_, slot) in slots {
+            if let Some(run) = runs.last_mut() {
+                if *run.0 == slot.source && run.1 == slot.lsb + 8 {
+                    run.1 = slot.lsb;
+                    continue;
+                }
+            }
+            runs.push((&slot.source, slot.lsb, slot.lsb + 8));
+        }
+
+        let mut value: Option<Expr> = None;
+        for (source, lsb, msb) in runs {
+            let part = if lsb == 0 && msb == source.bits() {
+                source.clone()
+            } else {
+                Expr::extract(source.clone(), lsb, msb)
+            };
+            value = Some(match value {
+                Some(high) => Expr::concat(high, part),
+                None => part,
+            });
+        }
+        value.map(|value| (value, symbolic))
+    }
+}
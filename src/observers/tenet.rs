@@ -0,0 +1,221 @@
+//! Tenet execution trace
+//!
+//! `TenetTraceHook` writes one line per executed instruction in the text
+//! format read by the Tenet trace explorer: the registers that changed since
+//! the previous instruction, followed by the memory accesses made by the
+//! instruction, e.g.
+//!
+//! ```text
+//! r0=0x20000010,pc=0x8000234,mr=0x20000010:efbeadde
+//! ```
+use std::io::Write;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use fugue::ir::{
+    Address,
+    Translator,
+    il::pcode::Operand,
+};
+use fugue::bytes::Order;
+use fuguex::concrete::hooks::{ClonableHookConcrete, HookConcrete};
+use fuguex::hooks::types::{HookAction, HookStepAction, HookOutcome, Error};
+use fuguex::state::{
+    AsState,
+    pcode::PCodeState, StateOps};
+use fuguex::machine::StepState;
+
+use crate::observers::trace::{StepFilter, TraceCollector, TraceError};
+use crate::utils::registers::{self, RegisterSet};
+use crate::utils::export::{TraceExportError, TraceWriter};
+
+#[derive(Debug, Clone, Default)]
+pub struct TenetTrace {
+    lines: Vec<String>,
+    pending: Option<String>,    // Line of the instruction being executed
+}
+
+impl TenetTrace {
+    /// Close the line of the last executed instruction
+    pub fn finish(&mut self) {
+        if let Some(line) = self.pending.take() {
+            self.lines.push(line);
+        }
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len() + self.pending.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+        for line in self.lines.iter().chain(self.pending.iter()) {
+            writeln!(writer, "{}", line)?;
+        }
+        writer.flush()
+    }
+
+    fn push_access(&mut self, kind: &str, address: u64, value: &[u8]) {
+        if let Some(line) = self.pending.as_mut() {
+            line.push_str(&format!(",{}={:#x}:", kind, address));
+            value.iter().for_each(|b| line.push_str(&format!("{:02x}", b)));
+        }
+    }
+}
+
+/// Tenet trace observer
+/// S: State
+/// O: Order
+pub struct TenetTraceHook<S, O> {
+    registers: Arc<Vec<(String, Operand)>>,
+    program_counter: String,
+    last_values: Vec<Option<Vec<u8>>>,  // Register values at the previous traced instruction
+    trace: Arc<Mutex<TenetTrace>>,
+    filter: StepFilter,
+    state: PhantomData<S>,
+    order: PhantomData<O>,
+}
+
+// NOTE: manual implementation avoids adding the trait bounds `S: Clone, O: Clone`.
+impl<S, O> Clone for TenetTraceHook<S, O> {
+    fn clone(&self) -> Self {
+        Self {
+            registers: self.registers.clone(),
+            program_counter: self.program_counter.clone(),
+            last_values: self.last_values.clone(),
+            trace: self.trace.clone(),
+            filter: self.filter.clone(),
+            state: PhantomData,
+            order: PhantomData,
+        }
+    }
+}
+
+impl<S, O> TenetTraceHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    /// registers: registers to snapshot, e.g. `RegisterSet::arm()`
+    /// translator: translator of the emulated architecture, used to resolve the registers
+    pub fn new(registers: &RegisterSet, translator: &Translator) -> Result<(TraceCollector<TenetTrace>, Self), TraceError> {
+        let resolved = registers.resolve(translator)
+            .map_err(TraceError::UnknownRegister)?;
+        let trace = Arc::new(Mutex::new(TenetTrace::default()));
+        let collector = TraceCollector::from_shared(trace.clone());
+
+        let observer = Self {
+            last_values: vec![None; resolved.len()],
+            registers: Arc::new(resolved),
+            program_counter: registers.program_counter().to_string(),
+            trace,
+            filter: collector.step_filter(),
+            state: PhantomData,
+            order: PhantomData,
+        };
+
+        Ok((collector, observer))
+    }
+}
+
+impl<S: 'static, O> HookConcrete for TenetTraceHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    type State = S;
+    type Error = TraceError;
+    type Outcome = String;
+
+    fn hook_architectural_step(&mut self, state: &mut Self::State, address: &Address, _step_state: &StepState)
+        -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>
+    {
+        if !self.filter.step(u64::from(address)) {
+            // Close the last traced line, accesses of this instruction are dropped
+            self.trace.lock().finish();
+            return Ok(HookStepAction::Pass.into());
+        }
+        // Only emit the registers that changed since the last traced instruction
+        let mut deltas = Vec::new();
+        for ((name, register), last_value) in self.registers.iter().zip(self.last_values.iter_mut()) {
+            if *name == self.program_counter {
+                continue;
+            }
+            let value = registers::read_register(state.state_ref(), register);
+            if value.is_some() && value != *last_value {
+                deltas.push(format!("{}={}", name, registers::value_to_hex::<O>(value.as_ref().unwrap())));
+                *last_value = value;
+            }
+        }
+        // The program counter is always present so each line can be located
+        deltas.push(format!("{}={:#x}", self.program_counter, u64::from(address)));
+
+        let mut trace = self.trace.lock();
+        trace.finish();
+        trace.pending = Some(deltas.join(","));
+
+        Ok(HookStepAction::Pass.into())
+    }
+
+    fn hook_memory_read(
+        &mut self,
+        state: &mut Self::State,
+        address: &Address,
+        size: usize,
+    ) -> Result<HookOutcome<HookAction<Self::Outcome>>, Error<Self::Error>> {
+        if !self.filter.traced() {
+            return Ok(HookAction::Pass.into());
+        }
+        let mut value = vec![0u8; size];
+        if state.state_ref().get_values(address, &mut value).is_ok() {
+            self.trace.lock().push_access("mr", u64::from(address), &value);
+        }
+        Ok(HookAction::Pass.into())
+    }
+
+    fn hook_memory_write(
+        &mut self,
+        _state: &mut Self::State,
+        address: &Address,
+        _size: usize,
+        value: &[u8],
+    ) -> Result<HookOutcome<HookAction<Self::Outcome>>, Error<Self::Error>> {
+        if self.filter.traced() {
+            self.trace.lock().push_access("mw", u64::from(address), value);
+        }
+        Ok(HookAction::Pass.into())
+    }
+}
+
+impl<S: 'static, O> ClonableHookConcrete for TenetTraceHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+}
+
+/// collect_tenet_trace_to_file()
+/// collector: TraceCollector with TenetTrace
+/// writer: compression, Tenet traces have no room for a metadata header
+/// file: trace in Tenet text format
+pub fn collect_tenet_trace_to_file(
+    mut collector: TraceCollector<TenetTrace>,
+    writer: &TraceWriter,
+    file: &str) -> Result<usize, TraceExportError> {
+
+    writer.check_headerless("Tenet")?;
+    let collect_res = collector.collect();
+    let written = writer.write_with_path(file, |sink| {
+        collect_res.write_to(sink)?;
+        Ok(collect_res.len())
+    })?;
+    log::info!("{} instructions has been logged", written);
+    Ok(written)
+}
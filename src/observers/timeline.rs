@@ -0,0 +1,331 @@
+//! Emulation timeline in Chrome Trace Event format
+//!
+//! `TimelineHook` follows the shadow call stack to record function spans,
+//! records MMIO accesses as instant events, and flags interrupts: control
+//! transfers made by an instruction without any branch operation. Values
+//! provided by a `DummyPeripheral` are merged in when the timeline is
+//! written. The clock is the instruction count, written as microseconds so
+//! Perfetto and chrome://tracing display one instruction per microsecond.
+//!
+//! The filter of the collector drops the events of the instructions it
+//! filters out; a function span is recorded if the call entering it is
+//! traced, so spans stay balanced. The clock and the shadow stack follow every
+//! instruction.
+use std::collections::HashMap;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use fugue::ir::{
+    Address,
+    il::pcode::PCodeOp,
+};
+use fugue::bytes::Order;
+use fuguex::concrete::hooks::{ClonableHookConcrete, HookConcrete};
+use fuguex::hooks::types::{HookAction, HookStepAction, HookOutcome, Error};
+use fuguex::state::{
+    AsState,
+    pcode::PCodeState, StateOps};
+use fuguex::machine::StepState;
+
+use crate::observers::callstack::{ShadowCallStack, StackEvent};
+use crate::observers::dummy_peripheral::SolveEvent;
+use crate::observers::trace::{StepFilter, TraceCollector, TraceError};
+use crate::utils::export::{TraceExportError, TraceWriter};
+
+const PID: u32 = 1;
+const TID_CPU: u32 = 1;
+const TID_PERIPHERALS: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TimelineEntry {
+    Enter { ts: u64, function: u64, call_site: u64 },
+    Leave { ts: u64, function: u64 },
+    Interrupt { ts: u64, from: u64, to: u64 },
+    Mmio { ts: u64, pc: u64, address: u64, write: bool, value: Option<u64> },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Timeline {
+    stack: ShadowCallStack,
+    spans: Vec<bool>,               // Whether the entry of each open frame was recorded
+    entries: Vec<TimelineEntry>,
+    mmio_ranges: Vec<(u64, u64)>,   // Inclusive
+    icount: u64,
+    pc: u64,
+    fallthrough: Option<u64>,       // Next address of the last instruction, if it has no branch
+}
+
+impl Timeline {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Take the events, the MMIO ranges, clock and call stack are kept so the run can go on
+    /// Each batch is a valid trace: the functions still running end with the drained
+    /// batch, and begin again at the same time in the next one
+    pub fn drain(&mut self) -> Timeline {
+        let entries = std::mem::take(&mut self.entries);
+        let drained = Timeline { entries, ..self.clone() };
+        for (frame, recorded) in self.stack.frames().iter().zip(self.spans.iter()) {
+            if *recorded {
+                self.entries.push(TimelineEntry::Enter { ts: self.icount, function: frame.function, call_site: frame.call_site });
+            }
+        }
+        drained
+    }
+
+    fn is_mmio(&self, address: u64) -> bool {
+        self.mmio_ranges.iter().any(|(min, max)| *min <= address && address <= *max)
+    }
+
+    // traced: whether the events of the instruction are recorded
+    fn step(&mut self, address: u64, step_state: &StepState, stack_pointer: Option<u64>, traced: bool) {
+        self.icount += 1;
+        self.pc = address;
+        let pcode = step_state.operations();
+
+        if let Some(fallthrough) = self.fallthrough {
+            if fallthrough != address && traced {
+                self.entries.push(TimelineEntry::Interrupt { ts: self.icount, from: fallthrough, to: address });
+            }
+        }
+
+        let first = self.stack.depth() == 0;
+        let event = self.stack.step(address, pcode, stack_pointer);
+        if first {
+            // Root frame of the shadow stack
+            self.enter(address, 0, traced);
+        }
+        match event {
+            Some(StackEvent::Call(frame)) => self.enter(frame.function, frame.call_site, traced),
+            Some(StackEvent::Return(popped)) => {
+                for frame in popped.iter().rev() {
+                    self.leave(frame.function);
+                }
+            },
+            Some(StackEvent::TailCall { replaced, function }) => {
+                self.leave(replaced.function);
+                self.enter(function, replaced.call_site, traced);
+            },
+            None => (),
+        }
+
+        let branches = pcode.operations().iter().any(|op| matches!(op,
+            PCodeOp::Branch { .. } | PCodeOp::CBranch { .. } | PCodeOp::IBranch { .. }
+            | PCodeOp::Call { .. } | PCodeOp::ICall { .. } | PCodeOp::Return { .. }));
+        self.fallthrough = if branches { None } else { Some(address + pcode.length() as u64) };
+    }
+
+    fn enter(&mut self, function: u64, call_site: u64, traced: bool) {
+        self.spans.push(traced);
+        if traced {
+            self.entries.push(TimelineEntry::Enter { ts: self.icount, function, call_site });
+        }
+    }
+
+    fn leave(&mut self, function: u64) {
+        if self.spans.pop().unwrap_or(false) {
+            self.entries.push(TimelineEntry::Leave { ts: self.icount, function });
+        }
+    }
+
+    fn record_mmio(&mut self, address: u64, write: bool, value: Option<u64>) {
+        self.entries.push(TimelineEntry::Mmio { ts: self.icount, pc: self.pc, address, write, value });
+    }
+
+    /// Write the timeline as Chrome Trace Event JSON
+    /// symbols: names of the functions, unnamed functions are written as addresses
+    /// solves: values provided by a DummyPeripheral, see `get_solve_events`
+    pub fn write_chrome_trace<W: Write + ?Sized>(&self, writer: &mut W, symbols: &HashMap<u64, String>, solves: &[SolveEvent]) -> std::io::Result<()> {
+        let name = |function: &u64| symbols.get(function).cloned().unwrap_or_else(|| format!("{:#x}", function));
+        let mut events: Vec<Value> = vec![
+            json!({"name": "process_name", "ph": "M", "pid": PID, "args": {"name": "emulator"}}),
+            json!({"name": "thread_name", "ph": "M", "pid": PID, "tid": TID_CPU, "args": {"name": "cpu"}}),
+            json!({"name": "thread_name", "ph": "M", "pid": PID, "tid": TID_PERIPHERALS, "args": {"name": "peripherals"}}),
+        ];
+
+        let mut open = Vec::new();
+        for entry in &self.entries {
+            match entry {
+                TimelineEntry::Enter { ts, function, call_site } => {
+                    open.push(*function);
+                    events.push(json!({"name": name(function), "cat": "function", "ph": "B", "ts": ts, "pid": PID, "tid": TID_CPU,
+                        "args": {"entry": format!("{:#x}", function), "call_site": format!("{:#x}", call_site)}}));
+                },
+                TimelineEntry::Leave { ts, function } => {
+                    open.pop();
+                    events.push(json!({"name": name(function), "cat": "function", "ph": "E", "ts": ts, "pid": PID, "tid": TID_CPU}));
+                },
+                TimelineEntry::Interrupt { ts, from, to } => {
+                    events.push(json!({"name": format!("interrupt {}", name(to)), "cat": "interrupt", "ph": "i", "s": "t", "ts": ts, "pid": PID, "tid": TID_CPU,
+                        "args": {"from": format!("{:#x}", from), "to": format!("{:#x}", to)}}));
+                },
+                TimelineEntry::Mmio { ts, pc, address, write, value } => {
+                    let kind = if *write { "write" } else { "read" };
+                    events.push(json!({"name": format!("{} {:#x}", kind, address), "cat": "mmio", "ph": "i", "s": "t", "ts": ts, "pid": PID, "tid": TID_PERIPHERALS,
+                        "args": {"pc": format!("{:#x}", pc), "value": value.map(|value| format!("{:#x}", value))}}));
+                },
+            }
+        }
+        for solve in solves {
+            let cat = if solve.cached { "solve_cached" } else { "solve" };
+            events.push(json!({"name": format!("solve {:#x}", solve.address), "cat": cat, "ph": "i", "s": "t", "ts": solve.icount, "pid": PID, "tid": TID_PERIPHERALS,
+                "args": {"pc": format!("{:#x}", solve.pc), "size": solve.size, "value": solve.value}}));
+        }
+        // Functions still running end with the trace
+        for function in open.iter().rev() {
+            events.push(json!({"name": name(function), "cat": "function", "ph": "E", "ts": self.icount, "pid": PID, "tid": TID_CPU}));
+        }
+
+        let trace = json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ns",
+            "otherData": {"clock": "instruction count"},
+        });
+        serde_json::to_writer(&mut *writer, &trace)?;
+        writer.flush()
+    }
+}
+
+/// Timeline observer
+/// S: State
+/// O: Order
+pub struct TimelineHook<S, O> {
+    timeline: Arc<Mutex<Timeline>>,
+    filter: StepFilter,
+    state: PhantomData<S>,
+    order: PhantomData<O>,
+}
+
+// NOTE: manual implementation avoids adding the trait bounds `S: Clone, O: Clone`.
+impl<S, O> Clone for TimelineHook<S, O> {
+    fn clone(&self) -> Self {
+        Self {
+            timeline: self.timeline.clone(),
+            filter: self.filter.clone(),
+            state: PhantomData,
+            order: PhantomData,
+        }
+    }
+}
+
+impl<S, O> TimelineHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    pub fn new() -> (TraceCollector<Timeline>, Self) {
+        let timeline = Arc::new(Mutex::new(Timeline::default()));
+        let collector = TraceCollector::from_shared(timeline.clone());
+
+        let observer = Self {
+            timeline,
+            filter: collector.step_filter(),
+            state: PhantomData,
+            order: PhantomData,
+        };
+
+        (collector, observer)
+    }
+
+    /// Record accesses inside the range as MMIO events, both ends included
+    /// e.g. the ranges of a DummyPeripheral
+    pub fn add_mmio_range<A>(&mut self, addr_range: (A, A)) where A: Into<Address> {
+        let (addr_start, addr_end) = addr_range;
+        self.timeline.lock().mmio_ranges.push((u64::from(addr_start.into()), u64::from(addr_end.into())));
+    }
+
+    fn value_of(value: &[u8]) -> Option<u64> {
+        if value.len() <= 8 {
+            Some(crate::utils::registers::value_to_u64::<O>(value))
+        } else {
+            None
+        }
+    }
+}
+
+impl<S: 'static, O> HookConcrete for TimelineHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    type State = S;
+    type Error = TraceError;
+    type Outcome = String;
+
+    fn hook_architectural_step(&mut self, state: &mut Self::State, address: &Address, step_state: &StepState)
+        -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>
+    {
+        let stack_pointer = state.state_ref().stack_pointer_value().ok().map(u64::from);
+        let traced = self.filter.step(u64::from(address));
+        self.timeline.lock().step(u64::from(address), step_state, stack_pointer, traced);
+        Ok(HookStepAction::Pass.into())
+    }
+
+    fn hook_memory_read(
+        &mut self,
+        state: &mut Self::State,
+        address: &Address,
+        size: usize,
+    ) -> Result<HookOutcome<HookAction<Self::Outcome>>, Error<Self::Error>> {
+        let offset = u64::from(address);
+        let mut timeline = self.timeline.lock();
+        if self.filter.traced() && timeline.is_mmio(offset) {
+            let mut value = vec![0u8; size];
+            let value = state.state_ref().get_values(address, &mut value).ok().and_then(|_| Self::value_of(&value));
+            timeline.record_mmio(offset, false, value);
+        }
+        Ok(HookAction::Pass.into())
+    }
+
+    fn hook_memory_write(
+        &mut self,
+        _state: &mut Self::State,
+        address: &Address,
+        _size: usize,
+        value: &[u8],
+    ) -> Result<HookOutcome<HookAction<Self::Outcome>>, Error<Self::Error>> {
+        let offset = u64::from(address);
+        let mut timeline = self.timeline.lock();
+        if self.filter.traced() && timeline.is_mmio(offset) {
+            timeline.record_mmio(offset, true, Self::value_of(value));
+        }
+        Ok(HookAction::Pass.into())
+    }
+}
+
+impl<S: 'static, O> ClonableHookConcrete for TimelineHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+}
+
+/// collect_timeline_to_file()
+/// collector: TraceCollector with Timeline
+/// writer: compression, Chrome JSON has no room for a metadata header
+/// symbols: names of the functions
+/// solves: values provided by a DummyPeripheral, empty if there is none
+/// file: trace in Chrome Trace Event JSON format, readable by Perfetto
+pub fn collect_timeline_to_file(
+    mut collector: TraceCollector<Timeline>,
+    writer: &TraceWriter,
+    symbols: &HashMap<u64, String>,
+    solves: &[SolveEvent],
+    file: &str) -> Result<usize, TraceExportError> {
+
+    writer.check_headerless("Chrome JSON")?;
+    let collect_res = collector.collect_mut(Timeline::drain);
+    let written = writer.write_with_path(file, |sink| {
+        collect_res.write_chrome_trace(sink, symbols, solves)?;
+        Ok(collect_res.len() + solves.len())
+    })?;
+    log::info!("{} timeline events has been logged", written);
+    Ok(written)
+}
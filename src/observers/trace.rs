@@ -13,13 +13,11 @@ use fuguex::state::{
 };
 use fuguex::machine::StepState;
 use fugue::bytes::{Order};
-use std::fs::File;
-use std::path::Path;
-use std::io::Write;
 // use muexe_taint::state::PCodeTaint;
 // use muexe_core_prelude::observers::{TraceHook};
-use protobuf::Message;
 use crate::utils::tbb;
+use crate::utils::export::{TraceExportError, TraceWriter};
+use crate::utils::drcov::{self, DrcovBlocks, DrcovModule};
 
 use std::marker::PhantomData;
 use std::mem::take;
@@ -33,14 +31,115 @@ pub enum TraceCollectorError {
 }
 
 
+/// Gate on the events delivered by a TraceHook
+/// Ranges are [start, end), an empty include list includes every address.
+/// With a start trigger, events are delivered from the first execution of
+/// the trigger address until the stop trigger is executed (excluded).
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    include: Vec<(u64, u64)>,
+    exclude: Vec<(u64, u64)>,
+    start_trigger: Option<u64>,
+    stop_trigger: Option<u64>,
+    triggered: bool,    // Start trigger hit since the last stop
+    stopped: bool,      // Stop trigger hit, without start trigger
+}
+
+impl TraceFilter {
+    pub fn include_range(&mut self, start: u64, end: u64) {
+        self.include.push((start, end));
+    }
+
+    pub fn exclude_range(&mut self, start: u64, end: u64) {
+        self.exclude.push((start, end));
+    }
+
+    /// Tracing is paused until `address` is executed
+    pub fn set_start_trigger(&mut self, address: Option<u64>) {
+        self.start_trigger = address;
+        self.triggered = false;
+        self.stopped = false;
+    }
+
+    /// Tracing is paused when `address` is executed, until the start trigger is hit again
+    pub fn set_stop_trigger(&mut self, address: Option<u64>) {
+        self.stop_trigger = address;
+        self.stopped = false;
+    }
+
+    /// Remove the ranges and triggers
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Tracing is enabled by the triggers
+    pub fn is_tracing(&self) -> bool {
+        if self.start_trigger.is_some() { self.triggered } else { !self.stopped }
+    }
+
+    /// Update the triggers and return whether the instruction at `address` is traced
+    pub fn step(&mut self, address: u64) -> bool {
+        if Some(address) == self.start_trigger {
+            self.triggered = true;
+        }
+        if Some(address) == self.stop_trigger {
+            self.triggered = false;
+            self.stopped = true;
+        }
+        if !self.is_tracing() {
+            return false;
+        }
+        let inside = |ranges: &[(u64, u64)]| ranges.iter().any(|(start, end)| *start <= address && address < *end);
+        (self.include.is_empty() || inside(&self.include)) && !inside(&self.exclude)
+    }
+}
+
+/// Filter of a TraceCollector, applied by observers other than TraceHook
+/// `step` is called on every instruction, the events of the instruction
+/// (e.g. its memory accesses) are only recorded if it is `traced`.
+#[derive(Debug, Clone)]
+pub(crate) struct StepFilter {
+    filter: Arc<Mutex<TraceFilter>>,
+    traced: bool,
+}
+
+impl StepFilter {
+    pub fn step(&mut self, address: u64) -> bool {
+        self.traced = self.filter.lock().step(address);
+        self.traced
+    }
+
+    /// Whether the current instruction is traced
+    pub fn traced(&self) -> bool {
+        self.traced
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct TraceCollector<E> {
     events: Arc<Mutex<E>>,
+    filter: Arc<Mutex<TraceFilter>>,
 }
 
 impl<E> TraceCollector<E>
 where E: Default + Send + Sync {
 
+    /// Handle on events shared with an observer other than TraceHook
+    /// The observer applies the filter of the handle through `step_filter`
+    pub(crate) fn from_shared(events: Arc<Mutex<E>>) -> Self {
+        Self { events, filter: Default::default() }
+    }
+
+    /// Filter of the handle, for the observer feeding the events
+    pub(crate) fn step_filter(&self) -> StepFilter {
+        StepFilter { filter: self.filter.clone(), traced: true }
+    }
+
+    /// Events shared with the observer, for observers feeding the same events
+    pub(crate) fn shared(&self) -> Arc<Mutex<E>> {
+        self.events.clone()
+    }
+
     /// Get Events
     pub fn collect(&mut self) -> E {
         let mut events = self.events.lock();
@@ -57,6 +156,35 @@ where E: Default + Send + Sync {
     where F: FnOnce(&mut E) -> O {
         f(&mut *self.events.lock())
     }
+
+    /// Only deliver events for addresses in [start, end), may be called several times
+    pub fn include_range(&self, start: u64, end: u64) {
+        self.filter.lock().include_range(start, end);
+    }
+
+    /// Drop events for addresses in [start, end)
+    pub fn exclude_range(&self, start: u64, end: u64) {
+        self.filter.lock().exclude_range(start, end);
+    }
+
+    pub fn set_start_trigger(&self, address: Option<u64>) {
+        self.filter.lock().set_start_trigger(address);
+    }
+
+    pub fn set_stop_trigger(&self, address: Option<u64>) {
+        self.filter.lock().set_stop_trigger(address);
+    }
+
+    /// Deliver every event again
+    pub fn clear_filters(&self) {
+        self.filter.lock().clear();
+    }
+
+    /// Run function on mut filter ref
+    pub fn filter_mut<F, O>(&self, f: F) -> O
+    where F: FnOnce(&mut TraceFilter) -> O {
+        f(&mut *self.filter.lock())
+    }
 }
 
 
@@ -66,6 +194,8 @@ pub enum TraceError {
     DefaultError(),
     #[error("Collector Error: {0}")]
     TraceCollectorError(#[from] TraceCollectorError),
+    #[error("Unknown register: {0}")]
+    UnknownRegister(String),
 }
 
 
@@ -90,6 +220,7 @@ impl From<TraceCollectorError> for HookError<TraceError> {
 pub struct TraceHook<S, O, E> {
     event_observer: Arc<dyn Fn(&Address, &PCode, &mut S, &mut E) -> Result<(), TraceCollectorError> + Send + Sync>,
     event_collector: Arc<Mutex<E>>,
+    filter: Arc<Mutex<TraceFilter>>,
     state: PhantomData<S>,
     order: PhantomData<O>,
 }
@@ -100,6 +231,7 @@ impl<S, O, E> Clone for TraceHook<S, O, E> {
         Self {
             event_observer: self.event_observer.clone(),
             event_collector: self.event_collector.clone(),
+            filter: self.filter.clone(),
             state: PhantomData,
             order: PhantomData,
         }
@@ -116,14 +248,24 @@ where
     pub fn new_unboxed<F>(processor: F) -> (TraceCollector<E>, Self)
         where F: Fn(&Address, &PCode, &mut S, &mut E) -> Result<(), TraceCollectorError> + Send + Sync + 'static 
     {
-        let event_collector = Arc::new(Mutex::new(Default::default()));
+        Self::with_events(Default::default(), processor)
+    }
+
+    /// Same as new_unboxed, starting from `events` instead of the default value
+    pub fn with_events<F>(events: E, processor: F) -> (TraceCollector<E>, Self)
+        where F: Fn(&Address, &PCode, &mut S, &mut E) -> Result<(), TraceCollectorError> + Send + Sync + 'static 
+    {
+        let event_collector = Arc::new(Mutex::new(events));
+        let filter = Arc::new(Mutex::new(TraceFilter::default()));
         let collector = TraceCollector {
             events: event_collector.clone(),
+            filter: filter.clone(),
         };
 
         let observer = Self {
             event_observer: Arc::new(processor),
             event_collector,
+            filter,
             state: PhantomData,
             order: PhantomData,
         };
@@ -144,6 +286,9 @@ where
     fn hook_architectural_step(&mut self, state: &mut Self::State, address: &Address, step_state: &StepState)
         -> Result<HookOutcome<HookStepAction<Self::Outcome>>, HookError<Self::Error>> 
     {
+        if !self.filter.lock().step(u64::from(address)) {
+            return Ok(HookStepAction::Pass.into());
+        }
         (*self.event_observer)(
             address,
             step_state.operations(),
@@ -183,38 +328,61 @@ pub fn get_tbb_tace_obs<O: Order>()->
 }
 /// collect_tbb_trace_to_file()
 /// collector: TraceCollector with TBBBlocks
+/// writer: compression, and metadata header of the tbb file; the plain file has no room for one
 /// file: trace in tbb format
 /// file_plain: Trace in plain test, record pc changes, this can be read by flow_color.py in Ghidra
+/// Use TraceWriter directly for other destinations
 pub fn collect_tbb_trace_to_file(
-	mut collector: TraceCollector<tbb::TBBBlocks>, 
-	file : Option<&str>, file_plain : Option<&str>){
+	mut collector: TraceCollector<tbb::TBBBlocks>,
+	writer: &TraceWriter,
+	file : Option<&str>, file_plain : Option<&str>) -> Result<usize, TraceExportError> {
 
+	if file_plain.is_some() {
+		writer.check_headerless("Plain TBB")?;
+	}
 	let collect_res = collector.collect();
 	// Write trace to file
-	if file != None {
-		// create trace file
-		let path = Path::new(file.unwrap());
-		let mut file = match File::create(&path){
-			Err(why) => panic!("couldn't open {}: {}", path.display(), why),
-			Ok(file) => file
-		};
-		// write trace
-		let trace_b = collect_res.write_to_bytes().expect("parse trace to byte failed");
-		file.write_all(&trace_b).expect("writting to trace file failed");
+	if let Some(file) = file {
+		writer.write_tbb_to_path(file, &collect_res)?;
 	}
 	// Trace in plain test, record pc changes
-	if file_plain != None {
-			// create trace file
-			let path = Path::new(file_plain.unwrap());
-			let mut file = match File::create(&path){
-				Err(why) => panic!("couldn't open {}: {}", path.display(), why),
-				Ok(file) => file
-			}; 
-			for i in collect_res.get_basic_blocks() {
-				let addr = i.get_address();
-				file.write(format!("{:#x}\n", addr).as_bytes()).expect("unable to write to file");
-			}
-
+	if let Some(file_plain) = file_plain {
+		writer.write_tbb_plain_to_path(file_plain, &collect_res)?;
 	}
-	println!("{:?} PC changes has been logged", collect_res.basic_blocks.len());
-}
\ No newline at end of file
+	log::info!("{:?} PC changes has been logged", collect_res.basic_blocks.len());
+	Ok(collect_res.basic_blocks.len())
+}
+
+pub fn get_drcov_trace_obs<O: Order>()->
+(TraceCollector<DrcovBlocks>,
+	TraceHook<PCodeState<u8, O>, O, DrcovBlocks>) {
+
+	TraceHook::new_unboxed(
+		|address,
+		pcode,
+		_state: & mut PCodeState<u8, O>,
+		collector: & mut DrcovBlocks | -> Result<(), TraceCollectorError>{
+			// Every executed instruction is recorded as a block of its own length
+			collector.add_block(u64::from(address), pcode.length() as u16);
+			Ok(())
+
+	})
+}
+/// collect_drcov_trace_to_file()
+/// collector: TraceCollector with DrcovBlocks
+/// writer: compression, drcov has no room for a metadata header
+/// modules: module table, one entry per loaded region of the firmware image
+/// file: coverage in drcov format, this can be read by Lighthouse in IDA and Lightkeeper in Ghidra
+pub fn collect_drcov_trace_to_file(
+	mut collector: TraceCollector<DrcovBlocks>,
+	writer: &TraceWriter,
+	modules: &[DrcovModule], file: &str) -> Result<usize, TraceExportError> {
+
+	writer.check_headerless("drcov")?;
+	let collect_res = collector.collect();
+	let written = writer.write_with_path(file, |sink| {
+		Ok(drcov::write_drcov(sink, modules, &collect_res)?)
+	})?;
+	log::info!("{} of {} covered blocks written to drcov file", written, collect_res.len());
+	Ok(written)
+}
@@ -0,0 +1,355 @@
+//! Value Change Dump of peripheral registers
+//!
+//! `VcdHook` follows the memory accesses made to peripheral registers and
+//! keeps the value of each register over time, with the instruction count as
+//! timebase. Registers come from the ranges of a `DummyPeripheral` (split in
+//! registers of a fixed size) or from an SVD file. Each register gets a
+//! companion `<name>_solved` signal, pulsed when a `DummyPeripheral` provided
+//! its value, so solver-injected values stand out in GTKWave.
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use fugue::ir::Address;
+use fugue::bytes::Order;
+use fuguex::concrete::hooks::{ClonableHookConcrete, HookConcrete};
+use fuguex::hooks::types::{HookAction, HookStepAction, HookOutcome, Error};
+use fuguex::state::{
+    AsState,
+    pcode::PCodeState, StateOps};
+use fuguex::machine::StepState;
+
+use crate::observers::dummy_peripheral::SolveEvent;
+use crate::observers::trace::{StepFilter, TraceCollector, TraceError};
+use crate::utils::export::{TraceExportError, TraceWriter};
+use crate::utils::registers;
+use crate::utils::svd::{self, SvdError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeripheralRegister {
+    pub scope: String,      // Peripheral name, one VCD scope per peripheral
+    pub name: String,
+    pub address: u64,
+    pub size: usize,        // In bytes, at most 8
+}
+
+/// Peripheral registers to dump, sorted by address
+#[derive(Debug, Clone, Default)]
+pub struct RegisterMap {
+    registers: Vec<PeripheralRegister>,
+}
+
+impl RegisterMap {
+    /// Split inclusive ranges, e.g. `DummyPeripheral::address_ranges`, in registers of `size` bytes
+    pub fn from_ranges<A>(ranges: &[(A, A)], size: usize) -> Self
+    where A: Clone + Into<Address> {
+        let size = size.clamp(1, 8);
+        let mut registers = Vec::new();
+        for (index, (start, end)) in ranges.iter().enumerate() {
+            let (start, end) = (u64::from(start.clone().into()), u64::from(end.clone().into()));
+            let scope = format!("peripheral{}", index);
+            let mut address = start;
+            while address <= end {
+                registers.push(PeripheralRegister {
+                    scope: scope.clone(),
+                    name: format!("reg_{:x}", address),
+                    address,
+                    size,
+                });
+                address += size as u64;
+            }
+        }
+        Self::from_registers(registers)
+    }
+
+    /// Registers of an SVD file
+    pub fn from_svd(text: &str) -> Result<Self, SvdError> {
+        let registers = svd::parse_svd(text)?.into_iter()
+            .filter(|register| (1..=8).contains(&register.size))
+            .map(|register| PeripheralRegister {
+                scope: register.peripheral,
+                name: register.name,
+                address: register.address,
+                size: register.size,
+            })
+            .collect();
+        Ok(Self::from_registers(registers))
+    }
+
+    pub fn from_registers(mut registers: Vec<PeripheralRegister>) -> Self {
+        registers.sort_by_key(|register| register.address);
+        registers.dedup_by_key(|register| register.address);
+        Self { registers }
+    }
+
+    pub fn registers(&self) -> &[PeripheralRegister] {
+        &self.registers
+    }
+
+    // Index of the register containing `address`
+    fn find(&self, address: u64) -> Option<usize> {
+        let index = match self.registers.binary_search_by(|register| register.address.cmp(&address)) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let register = &self.registers[index];
+        if address < register.address + register.size as u64 { Some(index) } else { None }
+    }
+}
+
+/// Values of the peripheral registers over time
+#[derive(Debug, Clone, Default)]
+pub struct RegisterActivity {
+    map: RegisterMap,
+    values: Vec<Option<Vec<u8>>>,           // Current bytes of each register, None until accessed
+    last: Vec<Option<u64>>,                 // Last value written to the dump
+    changes: Vec<(u64, usize, u64)>,        // (instruction count, register, value)
+    icount: u64,
+}
+
+impl RegisterActivity {
+    fn new(map: RegisterMap) -> Self {
+        Self {
+            values: vec![None; map.registers.len()],
+            last: vec![None; map.registers.len()],
+            map,
+            changes: Vec::new(),
+            icount: 0,
+        }
+    }
+
+    pub fn map(&self) -> &RegisterMap {
+        &self.map
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    // Apply the accessed bytes to the registers they overlap
+    fn access<O: Order>(&mut self, address: u64, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            let byte_address = address + i as u64;
+            if let Some(index) = self.map.find(byte_address) {
+                let register = &self.map.registers[index];
+                let value = self.values[index].get_or_insert_with(|| vec![0; register.size]);
+                let offset = (byte_address - register.address) as usize;
+                // Bytes are kept in memory order, so the value follows the byte order of the target
+                value[offset] = *byte;
+            }
+        }
+        let mut touched: Vec<usize> = (0..bytes.len() as u64)
+            .filter_map(|i| self.map.find(address + i))
+            .collect();
+        touched.dedup();
+        for index in touched {
+            let value = registers::value_to_u64::<O>(self.values[index].as_ref().unwrap());
+            if self.last[index] != Some(value) {
+                self.last[index] = Some(value);
+                self.changes.push((self.icount, index, value));
+            }
+        }
+    }
+
+    /// Write the activity as a VCD file
+    /// solves: values provided by a DummyPeripheral, marked on the `_solved` signals
+    pub fn write_vcd<W: Write + ?Sized>(&self, writer: &mut W, solves: &[SolveEvent]) -> std::io::Result<()> {
+        let registers = &self.map.registers;
+        // Two identifiers per register: the value and the solved marker
+        let identifier = |index: usize| vcd_identifier(index * 2);
+        let solved_identifier = |index: usize| vcd_identifier(index * 2 + 1);
+
+        writeln!(writer, "$comment fuguex peripheral register activity, one time unit per instruction $end")?;
+        writeln!(writer, "$timescale 1 ns $end")?;
+        writeln!(writer, "$scope module peripherals $end")?;
+        let mut scopes: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (index, register) in registers.iter().enumerate() {
+            scopes.entry(&register.scope).or_default().push(index);
+        }
+        for (scope, indices) in &scopes {
+            writeln!(writer, "$scope module {} $end", vcd_name(scope))?;
+            for index in indices {
+                let register = &registers[*index];
+                writeln!(writer, "$var wire {} {} {} $end", register.size * 8, identifier(*index), vcd_name(&register.name))?;
+                writeln!(writer, "$var wire 1 {} {}_solved $end", solved_identifier(*index), vcd_name(&register.name))?;
+            }
+            writeln!(writer, "$upscope $end")?;
+        }
+        writeln!(writer, "$upscope $end")?;
+        writeln!(writer, "$enddefinitions $end")?;
+
+        writeln!(writer, "#0")?;
+        writeln!(writer, "$dumpvars")?;
+        for index in 0..registers.len() {
+            writeln!(writer, "bx {}", identifier(index))?;
+            writeln!(writer, "0{}", solved_identifier(index))?;
+        }
+        writeln!(writer, "$end")?;
+
+        // Solve markers last one instruction
+        let mut events: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        for (icount, index, value) in &self.changes {
+            events.entry(*icount).or_default().push(format!("b{:b} {}", value, identifier(*index)));
+        }
+        for solve in solves {
+            if let Some(index) = self.map.find(solve.address) {
+                events.entry(solve.icount).or_default().push(format!("1{}", solved_identifier(index)));
+                events.entry(solve.icount + 1).or_default().push(format!("0{}", solved_identifier(index)));
+            }
+        }
+        for (icount, changes) in events {
+            writeln!(writer, "#{}", icount)?;
+            for change in changes {
+                writeln!(writer, "{}", change)?;
+            }
+        }
+        writeln!(writer, "#{}", self.icount.max(1))?;
+        writer.flush()
+    }
+}
+
+// Short identifier made of printable ASCII characters
+fn vcd_identifier(mut index: usize) -> String {
+    let mut identifier = String::new();
+    loop {
+        identifier.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return identifier;
+        }
+        index -= 1;
+    }
+}
+
+// VCD references cannot contain whitespace
+fn vcd_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_whitespace() { '_' } else { c }).collect()
+}
+
+/// Peripheral register observer
+/// S: State
+/// O: Order
+pub struct VcdHook<S, O> {
+    activity: Arc<Mutex<RegisterActivity>>,
+    filter: StepFilter,         // Accesses of the instructions filtered out are not recorded
+    state: PhantomData<S>,
+    order: PhantomData<O>,
+}
+
+// NOTE: manual implementation avoids adding the trait bounds `S: Clone, O: Clone`.
+impl<S, O> Clone for VcdHook<S, O> {
+    fn clone(&self) -> Self {
+        Self {
+            activity: self.activity.clone(),
+            filter: self.filter.clone(),
+            state: PhantomData,
+            order: PhantomData,
+        }
+    }
+}
+
+impl<S, O> VcdHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    /// map: registers to follow, see `RegisterMap::from_ranges` and `RegisterMap::from_svd`
+    pub fn new(map: RegisterMap) -> (TraceCollector<RegisterActivity>, Self) {
+        let activity = Arc::new(Mutex::new(RegisterActivity::new(map)));
+        let collector = TraceCollector::from_shared(activity.clone());
+
+        let observer = Self {
+            activity,
+            filter: collector.step_filter(),
+            state: PhantomData,
+            order: PhantomData,
+        };
+
+        (collector, observer)
+    }
+}
+
+impl<S: 'static, O> HookConcrete for VcdHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+    type State = S;
+    type Error = TraceError;
+    type Outcome = String;
+
+    fn hook_architectural_step(&mut self, _state: &mut Self::State, address: &Address, _step_state: &StepState)
+        -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>
+    {
+        self.filter.step(u64::from(address));
+        self.activity.lock().icount += 1;
+        Ok(HookStepAction::Pass.into())
+    }
+
+    fn hook_memory_read(
+        &mut self,
+        state: &mut Self::State,
+        address: &Address,
+        size: usize,
+    ) -> Result<HookOutcome<HookAction<Self::Outcome>>, Error<Self::Error>> {
+        if !self.filter.traced() {
+            return Ok(HookAction::Pass.into());
+        }
+        let mut activity = self.activity.lock();
+        let offset = u64::from(address);
+        if (0..size as u64).any(|i| activity.map.find(offset + i).is_some()) {
+            let mut value = vec![0u8; size];
+            if state.state_ref().get_values(address, &mut value).is_ok() {
+                activity.access::<O>(offset, &value);
+            }
+        }
+        Ok(HookAction::Pass.into())
+    }
+
+    fn hook_memory_write(
+        &mut self,
+        _state: &mut Self::State,
+        address: &Address,
+        _size: usize,
+        value: &[u8],
+    ) -> Result<HookOutcome<HookAction<Self::Outcome>>, Error<Self::Error>> {
+        if self.filter.traced() {
+            self.activity.lock().access::<O>(u64::from(address), value);
+        }
+        Ok(HookAction::Pass.into())
+    }
+}
+
+impl<S: 'static, O> ClonableHookConcrete for VcdHook<S, O>
+where
+    S: AsState<PCodeState<u8, O>> + StateOps,
+    O: Order,
+{
+}
+
+/// collect_vcd_to_file()
+/// collector: TraceCollector with RegisterActivity
+/// writer: compression, VCD has no room for a metadata header
+/// solves: values provided by a DummyPeripheral, empty if there is none
+/// file: Value Change Dump, e.g. for GTKWave
+pub fn collect_vcd_to_file(
+    mut collector: TraceCollector<RegisterActivity>,
+    writer: &TraceWriter,
+    solves: &[SolveEvent],
+    file: &str) -> Result<usize, TraceExportError> {
+
+    writer.check_headerless("VCD")?;
+    let collect_res = collector.collect();
+    let written = writer.write_with_path(file, |sink| {
+        collect_res.write_vcd(sink, solves)?;
+        Ok(collect_res.len())
+    })?;
+    log::info!("{} register value changes has been logged", written);
+    Ok(written)
+}
@@ -0,0 +1,121 @@
+//! drcov coverage format
+//!
+//! Writer for the drcov (version 2) format used by Lighthouse (IDA) and
+//! Lightkeeper (Ghidra). A drcov file is a text header holding the module
+//! table, followed by a binary table of covered basic blocks, each stored
+//! as an offset relative to the base of the module it belongs to.
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+/// A loaded region of the firmware image, i.e. a row of the module table.
+/// Firmware made of several regions (flash, ROM, RAM code, ...) should
+/// register one module per region.
+#[derive(Debug, Clone)]
+pub struct DrcovModule {
+    pub path: String,   // Matched against the database name by Lighthouse/Lightkeeper
+    pub base: u64,
+    pub size: u64,
+    pub entry: u64,
+}
+
+impl DrcovModule {
+    pub fn new<P: Into<String>>(path: P, base: u64, size: u64) -> Self {
+        Self {
+            path: path.into(),
+            base,
+            size,
+            entry: 0,
+        }
+    }
+
+    pub fn with_entry(mut self, entry: u64) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.base && address - self.base < self.size
+    }
+
+    pub fn end(&self) -> u64 {
+        self.base + self.size
+    }
+}
+
+/// Covered blocks in the order they are first executed
+/// Each block is only recorded once
+#[derive(Debug, Clone, Default)]
+pub struct DrcovBlocks {
+    blocks: Vec<(u64, u16)>,    // (address, size in bytes)
+    seen: HashSet<u64>,
+}
+
+impl DrcovBlocks {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add_block(&mut self, address: u64, size: u16) {
+        if self.seen.insert(address) {
+            self.blocks.push((address, size));
+        }
+    }
+
+    pub fn blocks(&self) -> &[(u64, u16)] {
+        &self.blocks
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// write_drcov()
+/// writer: destination of the drcov file
+/// modules: module table, blocks outside of every module are dropped
+/// blocks: covered blocks
+/// Return the number of blocks written
+pub fn write_drcov<W: Write + ?Sized>(writer: &mut W, modules: &[DrcovModule], blocks: &DrcovBlocks) -> io::Result<usize> {
+    // Resolve the module of each block first, the header needs the final count
+    let mut entries = Vec::with_capacity(blocks.len());
+    for (address, size) in blocks.blocks() {
+        match modules.iter().position(|module| module.contains(*address)) {
+            Some(id) => {
+                let offset = address - modules[id].base;
+                if offset > u32::MAX as u64 {
+                    log::warn!("drcov: block {:#x} is too far from the module base, skipped", address);
+                    continue;
+                }
+                entries.push((offset as u32, *size, id as u16));
+            },
+            None => {
+                log::trace!("drcov: block {:#x} is not in any module, skipped", address);
+            }
+        }
+    }
+
+    // Text header
+    writeln!(writer, "DRCOV VERSION: 2")?;
+    writeln!(writer, "DRCOV FLAVOR: drcov")?;
+    writeln!(writer, "Module Table: version 2, count {}", modules.len())?;
+    writeln!(writer, "Columns: id, base, end, entry, checksum, timestamp, path")?;
+    for (id, module) in modules.iter().enumerate() {
+        writeln!(writer, "{:3}, {:#018x}, {:#018x}, {:#018x}, {:#010x}, {:#010x}, {}",
+            id, module.base, module.end(), module.entry, 0, 0, module.path)?;
+    }
+    writeln!(writer, "BB Table: {} bbs", entries.len())?;
+
+    // Binary table: struct { u32 start; u16 size; u16 mod_id; }, little endian
+    for (offset, size, id) in &entries {
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.write_all(&size.to_le_bytes())?;
+        writer.write_all(&id.to_le_bytes())?;
+    }
+    writer.flush()?;
+
+    Ok(entries.len())
+}
@@ -0,0 +1,243 @@
+//! Trace file export
+//!
+//! `TraceWriter` writes collected traces to any `io::Write` or to a path,
+//! optionally compressed with gzip or zstd and preceded by a metadata header
+//! describing the run. Every failure is reported as a `TraceExportError`.
+//!
+//! Binary traces (e.g. protobuf) start with the header
+//! `FXTRACE1 | u32 LE length | metadata as JSON`, text traces with a
+//! `# fuguex-trace: <metadata as JSON>` line. Compression applies to the
+//! whole file, header included. Formats read by other tools with no room for
+//! a header (drcov, JSON, VCD, Tenet, plain TBB) fail with
+//! `MetadataUnsupported` rather than dropping the metadata.
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use protobuf::Message;
+
+use crate::utils::tbb;
+
+pub const TRACE_MAGIC: &[u8; 8] = b"FXTRACE1";
+
+#[derive(Debug, Error)]
+pub enum TraceExportError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Encoding error: {0}")]
+    Encoding(#[from] protobuf::ProtobufError),
+    #[error("JSON encoding error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0} traces cannot carry a metadata header")]
+    MetadataUnsupported(&'static str),
+    #[error("Partial write: {written} of {expected} bytes written: {source}")]
+    PartialWrite {
+        written: usize,
+        expected: usize,
+        source: io::Error,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Description of the run a trace comes from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceMetadata {
+    pub architecture: String,       // e.g. "ARM:LE:32:Cortex"
+    pub endianness: String,         // "little" or "big"
+    pub image_hash: Option<String>, // SHA-256 of the firmware image
+    pub tool_version: String,
+}
+
+impl TraceMetadata {
+    pub fn new<A: Into<String>>(architecture: A, little_endian: bool) -> Self {
+        Self {
+            architecture: architecture.into(),
+            endianness: if little_endian { "little" } else { "big" }.to_string(),
+            image_hash: None,
+            tool_version: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        }
+    }
+
+    pub fn with_image(mut self, image: &[u8]) -> Self {
+        let digest = Sha256::digest(image);
+        self.image_hash = Some(digest.iter().map(|b| format!("{:02x}", b)).collect());
+        self
+    }
+
+    pub fn with_image_file<P: AsRef<Path>>(self, path: P) -> Result<Self, TraceExportError> {
+        let mut image = Vec::new();
+        File::open(path)?.read_to_end(&mut image)?;
+        Ok(self.with_image(&image))
+    }
+}
+
+// Count the bytes that reached the underlying writer, to report partial writes
+struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    written: usize,
+}
+
+impl<'a> Write for CountingWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TraceWriter {
+    compression: Compression,
+    metadata: Option<TraceMetadata>,
+}
+
+impl TraceWriter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn metadata(mut self, metadata: TraceMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Run `f` on the (possibly compressing) sink wrapping `writer`
+    /// `f` returns the number of records it wrote, which is returned on success
+    pub fn write_with<W, F>(&self, writer: W, f: F) -> Result<usize, TraceExportError>
+    where
+        W: Write,
+        F: FnOnce(&mut dyn Write) -> Result<usize, TraceExportError>,
+    {
+        match self.compression {
+            Compression::None => {
+                let mut writer = writer;
+                let count = f(&mut writer)?;
+                writer.flush()?;
+                Ok(count)
+            },
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+                let count = f(&mut encoder)?;
+                encoder.finish()?.flush()?;
+                Ok(count)
+            },
+            Compression::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(writer, 0)?;
+                let count = f(&mut encoder)?;
+                encoder.finish()?.flush()?;
+                Ok(count)
+            },
+        }
+    }
+
+    /// Same as `write_with`, creating the file at `path`
+    pub fn write_with_path<P, F>(&self, path: P, f: F) -> Result<usize, TraceExportError>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut dyn Write) -> Result<usize, TraceExportError>,
+    {
+        let file = File::create(path)?;
+        self.write_with(BufWriter::new(file), f)
+    }
+
+    fn write_payload(sink: &mut dyn Write, payload: &[u8]) -> Result<(), TraceExportError> {
+        let mut counter = CountingWriter { inner: sink, written: 0 };
+        counter.write_all(payload).map_err(|source| {
+            if counter.written == 0 {
+                TraceExportError::Io(source)
+            } else {
+                TraceExportError::PartialWrite {
+                    written: counter.written,
+                    expected: payload.len(),
+                    source,
+                }
+            }
+        })
+    }
+
+    /// Header of binary traces, nothing if no metadata is set
+    pub fn write_binary_header(&self, sink: &mut dyn Write) -> Result<(), TraceExportError> {
+        if let Some(metadata) = &self.metadata {
+            let json = serde_json::to_vec(metadata)?;
+            sink.write_all(TRACE_MAGIC)?;
+            sink.write_all(&(json.len() as u32).to_le_bytes())?;
+            Self::write_payload(sink, &json)?;
+        }
+        Ok(())
+    }
+
+    /// Header of text traces, nothing if no metadata is set
+    pub fn write_text_header(&self, sink: &mut dyn Write) -> Result<(), TraceExportError> {
+        if let Some(metadata) = &self.metadata {
+            writeln!(sink, "# fuguex-trace: {}", serde_json::to_string(metadata)?)?;
+        }
+        Ok(())
+    }
+
+    /// For formats without a header: fail if metadata is set, instead of dropping it
+    pub fn check_headerless(&self, format: &'static str) -> Result<(), TraceExportError> {
+        match self.metadata {
+            Some(_) => Err(TraceExportError::MetadataUnsupported(format)),
+            None => Ok(()),
+        }
+    }
+
+    /// Write a protobuf message, e.g. TBBBlocks or MemAccesses
+    /// Return the size of the encoded message
+    pub fn write_protobuf<W: Write, M: Message>(&self, writer: W, message: &M) -> Result<usize, TraceExportError> {
+        let payload = message.write_to_bytes()?;
+        self.write_with(writer, |sink| {
+            self.write_binary_header(sink)?;
+            Self::write_payload(sink, &payload)?;
+            Ok(payload.len())
+        })
+    }
+
+    /// Write a TBB trace in protobuf format
+    /// Return the number of blocks written
+    pub fn write_tbb<W: Write>(&self, writer: W, blocks: &tbb::TBBBlocks) -> Result<usize, TraceExportError> {
+        self.write_protobuf(writer, blocks)?;
+        Ok(blocks.basic_blocks.len())
+    }
+
+    /// Write a TBB trace in plain text, one PC per line, this can be read by flow_color.py in Ghidra
+    /// flow_color.py reads every line as a PC: fails if metadata is set
+    /// Return the number of blocks written
+    pub fn write_tbb_plain<W: Write>(&self, writer: W, blocks: &tbb::TBBBlocks) -> Result<usize, TraceExportError> {
+        self.check_headerless("Plain TBB")?;
+        self.write_with(writer, |sink| {
+            for block in blocks.get_basic_blocks() {
+                writeln!(sink, "{:#x}", block.get_address())?;
+            }
+            Ok(blocks.basic_blocks.len())
+        })
+    }
+
+    pub fn write_tbb_to_path<P: AsRef<Path>>(&self, path: P, blocks: &tbb::TBBBlocks) -> Result<usize, TraceExportError> {
+        let file = File::create(path)?;
+        self.write_tbb(BufWriter::new(file), blocks)
+    }
+
+    pub fn write_tbb_plain_to_path<P: AsRef<Path>>(&self, path: P, blocks: &tbb::TBBBlocks) -> Result<usize, TraceExportError> {
+        let file = File::create(path)?;
+        self.write_tbb_plain(BufWriter::new(file), blocks)
+    }
+}
@@ -0,0 +1,638 @@
+// This file is generated by rust-protobuf 2.25.2. Do not edit
+// @generated
+
+// https://github.com/rust-lang/rust-clippy/issues/702
+#![allow(unknown_lints)]
+#![allow(clippy::all)]
+
+#![allow(unused_attributes)]
+#![cfg_attr(rustfmt, rustfmt::skip)]
+
+#![allow(box_pointers)]
+#![allow(dead_code)]
+#![allow(missing_docs)]
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+#![allow(non_upper_case_globals)]
+#![allow(trivial_casts)]
+#![allow(unused_imports)]
+#![allow(unused_results)]
+//! Generated file from `memtrace.proto`
+
+/// Generated files are compatible only with the same version
+/// of protobuf runtime.
+// const _PROTOBUF_VERSION_CHECK: () = ::protobuf::VERSION_2_25_2;
+
+#[derive(PartialEq,Clone,Default)]
+pub struct MemAccesses {
+    // message fields
+    pub accesses: ::protobuf::RepeatedField<MemAccess>,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a MemAccesses {
+    fn default() -> &'a MemAccesses {
+        <MemAccesses as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl MemAccesses {
+    pub fn new() -> MemAccesses {
+        ::std::default::Default::default()
+    }
+
+    // repeated .MemAccess accesses = 1;
+
+
+    pub fn get_accesses(&self) -> &[MemAccess] {
+        &self.accesses
+    }
+    pub fn clear_accesses(&mut self) {
+        self.accesses.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_accesses(&mut self, v: ::protobuf::RepeatedField<MemAccess>) {
+        self.accesses = v;
+    }
+
+    // Mutable pointer to the field.
+    pub fn mut_accesses(&mut self) -> &mut ::protobuf::RepeatedField<MemAccess> {
+        &mut self.accesses
+    }
+
+    // Take field
+    pub fn take_accesses(&mut self) -> ::protobuf::RepeatedField<MemAccess> {
+        ::std::mem::replace(&mut self.accesses, ::protobuf::RepeatedField::new())
+    }
+}
+
+impl ::protobuf::Message for MemAccesses {
+    fn is_initialized(&self) -> bool {
+        for v in &self.accesses {
+            if !v.is_initialized() {
+                return false;
+            }
+        };
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    ::protobuf::rt::read_repeated_message_into(wire_type, is, &mut self.accesses)?;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        for value in &self.accesses {
+            let len = value.compute_size();
+            my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
+        };
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        for v in &self.accesses {
+            os.write_tag(1, ::protobuf::wire_format::WireTypeLengthDelimited)?;
+            os.write_raw_varint32(v.get_cached_size())?;
+            v.write_to_with_cached_sizes(os)?;
+        };
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> MemAccesses {
+        MemAccesses::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_repeated_field_accessor::<_, ::protobuf::types::ProtobufTypeMessage<MemAccess>>(
+                "accesses",
+                |m: &MemAccesses| { &m.accesses },
+                |m: &mut MemAccesses| { &mut m.accesses },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<MemAccesses>(
+                "MemAccesses",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static MemAccesses {
+        static instance: ::protobuf::rt::LazyV2<MemAccesses> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(MemAccesses::new)
+    }
+}
+
+impl ::protobuf::Clear for MemAccesses {
+    fn clear(&mut self) {
+        self.accesses.clear();
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for MemAccesses {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for MemAccesses {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct MemAccess {
+    // message fields
+    icount: ::std::option::Option<u64>,
+    pc: ::std::option::Option<u64>,
+    address: ::std::option::Option<u64>,
+    size: ::std::option::Option<u32>,
+    value: ::protobuf::SingularField<::std::vec::Vec<u8>>,
+    kind: ::std::option::Option<MemAccessKind>,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a MemAccess {
+    fn default() -> &'a MemAccess {
+        <MemAccess as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl MemAccess {
+    pub fn new() -> MemAccess {
+        ::std::default::Default::default()
+    }
+
+    // required uint64 icount = 1;
+
+
+    pub fn get_icount(&self) -> u64 {
+        self.icount.unwrap_or(0)
+    }
+    pub fn clear_icount(&mut self) {
+        self.icount = ::std::option::Option::None;
+    }
+
+    pub fn has_icount(&self) -> bool {
+        self.icount.is_some()
+    }
+
+    // Param is passed by value, moved
+    pub fn set_icount(&mut self, v: u64) {
+        self.icount = ::std::option::Option::Some(v);
+    }
+
+    // required uint64 pc = 2;
+
+
+    pub fn get_pc(&self) -> u64 {
+        self.pc.unwrap_or(0)
+    }
+    pub fn clear_pc(&mut self) {
+        self.pc = ::std::option::Option::None;
+    }
+
+    pub fn has_pc(&self) -> bool {
+        self.pc.is_some()
+    }
+
+    // Param is passed by value, moved
+    pub fn set_pc(&mut self, v: u64) {
+        self.pc = ::std::option::Option::Some(v);
+    }
+
+    // required uint64 address = 3;
+
+
+    pub fn get_address(&self) -> u64 {
+        self.address.unwrap_or(0)
+    }
+    pub fn clear_address(&mut self) {
+        self.address = ::std::option::Option::None;
+    }
+
+    pub fn has_address(&self) -> bool {
+        self.address.is_some()
+    }
+
+    // Param is passed by value, moved
+    pub fn set_address(&mut self, v: u64) {
+        self.address = ::std::option::Option::Some(v);
+    }
+
+    // required uint32 size = 4;
+
+
+    pub fn get_size(&self) -> u32 {
+        self.size.unwrap_or(0)
+    }
+    pub fn clear_size(&mut self) {
+        self.size = ::std::option::Option::None;
+    }
+
+    pub fn has_size(&self) -> bool {
+        self.size.is_some()
+    }
+
+    // Param is passed by value, moved
+    pub fn set_size(&mut self, v: u32) {
+        self.size = ::std::option::Option::Some(v);
+    }
+
+    // optional bytes value = 5;
+
+
+    pub fn get_value(&self) -> &[u8] {
+        match self.value.as_ref() {
+            Some(v) => &v,
+            None => &[],
+        }
+    }
+    pub fn clear_value(&mut self) {
+        self.value.clear();
+    }
+
+    pub fn has_value(&self) -> bool {
+        self.value.is_some()
+    }
+
+    // Param is passed by value, moved
+    pub fn set_value(&mut self, v: ::std::vec::Vec<u8>) {
+        self.value = ::protobuf::SingularField::some(v);
+    }
+
+    // Mutable pointer to the field.
+    // If field is not initialized, it is initialized with default value first.
+    pub fn mut_value(&mut self) -> &mut ::std::vec::Vec<u8> {
+        if self.value.is_none() {
+            self.value.set_default();
+        }
+        self.value.as_mut().unwrap()
+    }
+
+    // Take field
+    pub fn take_value(&mut self) -> ::std::vec::Vec<u8> {
+        self.value.take().unwrap_or_else(|| ::std::vec::Vec::new())
+    }
+
+    // required .MemAccessKind kind = 6;
+
+
+    pub fn get_kind(&self) -> MemAccessKind {
+        self.kind.unwrap_or(MemAccessKind::READ)
+    }
+    pub fn clear_kind(&mut self) {
+        self.kind = ::std::option::Option::None;
+    }
+
+    pub fn has_kind(&self) -> bool {
+        self.kind.is_some()
+    }
+
+    // Param is passed by value, moved
+    pub fn set_kind(&mut self, v: MemAccessKind) {
+        self.kind = ::std::option::Option::Some(v);
+    }
+}
+
+impl ::protobuf::Message for MemAccess {
+    fn is_initialized(&self) -> bool {
+        if self.icount.is_none() {
+            return false;
+        }
+        if self.pc.is_none() {
+            return false;
+        }
+        if self.address.is_none() {
+            return false;
+        }
+        if self.size.is_none() {
+            return false;
+        }
+        if self.kind.is_none() {
+            return false;
+        }
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.icount = ::std::option::Option::Some(tmp);
+                },
+                2 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.pc = ::std::option::Option::Some(tmp);
+                },
+                3 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.address = ::std::option::Option::Some(tmp);
+                },
+                4 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint32()?;
+                    self.size = ::std::option::Option::Some(tmp);
+                },
+                5 => {
+                    ::protobuf::rt::read_singular_bytes_into(wire_type, is, &mut self.value)?;
+                },
+                6 => {
+                    ::protobuf::rt::read_proto2_enum_with_unknown_fields_into(wire_type, is, &mut self.kind, 6, &mut self.unknown_fields)?
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if let Some(v) = self.icount {
+            my_size += ::protobuf::rt::value_size(1, v, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if let Some(v) = self.pc {
+            my_size += ::protobuf::rt::value_size(2, v, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if let Some(v) = self.address {
+            my_size += ::protobuf::rt::value_size(3, v, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if let Some(v) = self.size {
+            my_size += ::protobuf::rt::value_size(4, v, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if let Some(ref v) = self.value.as_ref() {
+            my_size += ::protobuf::rt::bytes_size(5, &v);
+        }
+        if let Some(v) = self.kind {
+            my_size += ::protobuf::rt::enum_size(6, v);
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if let Some(v) = self.icount {
+            os.write_uint64(1, v)?;
+        }
+        if let Some(v) = self.pc {
+            os.write_uint64(2, v)?;
+        }
+        if let Some(v) = self.address {
+            os.write_uint64(3, v)?;
+        }
+        if let Some(v) = self.size {
+            os.write_uint32(4, v)?;
+        }
+        if let Some(ref v) = self.value.as_ref() {
+            os.write_bytes(5, &v)?;
+        }
+        if let Some(v) = self.kind {
+            os.write_enum(6, ::protobuf::ProtobufEnum::value(&v))?;
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> MemAccess {
+        MemAccess::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_option_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "icount",
+                |m: &MemAccess| { &m.icount },
+                |m: &mut MemAccess| { &mut m.icount },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_option_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "pc",
+                |m: &MemAccess| { &m.pc },
+                |m: &mut MemAccess| { &mut m.pc },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_option_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "address",
+                |m: &MemAccess| { &m.address },
+                |m: &mut MemAccess| { &mut m.address },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_option_accessor::<_, ::protobuf::types::ProtobufTypeUint32>(
+                "size",
+                |m: &MemAccess| { &m.size },
+                |m: &mut MemAccess| { &mut m.size },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_singular_field_accessor::<_, ::protobuf::types::ProtobufTypeBytes>(
+                "value",
+                |m: &MemAccess| { &m.value },
+                |m: &mut MemAccess| { &mut m.value },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_option_accessor::<_, ::protobuf::types::ProtobufTypeEnum<MemAccessKind>>(
+                "kind",
+                |m: &MemAccess| { &m.kind },
+                |m: &mut MemAccess| { &mut m.kind },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<MemAccess>(
+                "MemAccess",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static MemAccess {
+        static instance: ::protobuf::rt::LazyV2<MemAccess> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(MemAccess::new)
+    }
+}
+
+impl ::protobuf::Clear for MemAccess {
+    fn clear(&mut self) {
+        self.icount = ::std::option::Option::None;
+        self.pc = ::std::option::Option::None;
+        self.address = ::std::option::Option::None;
+        self.size = ::std::option::Option::None;
+        self.value.clear();
+        self.kind = ::std::option::Option::None;
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for MemAccess {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for MemAccess {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(Clone,PartialEq,Eq,Debug,Hash)]
+pub enum MemAccessKind {
+    READ = 0,
+    WRITE = 1,
+}
+
+impl ::protobuf::ProtobufEnum for MemAccessKind {
+    fn value(&self) -> i32 {
+        *self as i32
+    }
+
+    fn from_i32(value: i32) -> ::std::option::Option<MemAccessKind> {
+        match value {
+            0 => ::std::option::Option::Some(MemAccessKind::READ),
+            1 => ::std::option::Option::Some(MemAccessKind::WRITE),
+            _ => ::std::option::Option::None
+        }
+    }
+
+    fn values() -> &'static [Self] {
+        static values: &'static [MemAccessKind] = &[
+            MemAccessKind::READ,
+            MemAccessKind::WRITE,
+        ];
+        values
+    }
+
+    fn enum_descriptor_static() -> &'static ::protobuf::reflect::EnumDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::EnumDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            ::protobuf::reflect::EnumDescriptor::new_pb_name::<MemAccessKind>("MemAccessKind", file_descriptor_proto())
+        })
+    }
+}
+
+impl ::std::marker::Copy for MemAccessKind {
+}
+
+impl ::std::default::Default for MemAccessKind {
+    fn default() -> Self {
+        MemAccessKind::READ
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for MemAccessKind {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Enum(::protobuf::ProtobufEnum::descriptor(self))
+    }
+}
+
+static file_descriptor_proto_data: &'static [u8] = b"\
+    \n\x0ememtrace.proto\"9\n\x0bMemAccesses\x12(\n\x08accesses\x18\x01\x20\
+    \x03(\x0b2\n.MemAccessR\x08accessesB\0:\0\"\xa9\x01\n\tMemAccess\x12\x18\
+    \n\x06icount\x18\x01\x20\x02(\x04R\x06icountB\0\x12\x10\n\x02pc\x18\x02\
+    \x20\x02(\x04R\x02pcB\0\x12\x1a\n\x07address\x18\x03\x20\x02(\x04R\x07ad\
+    dressB\0\x12\x14\n\x04size\x18\x04\x20\x02(\rR\x04sizeB\0\x12\x16\n\x05v\
+    alue\x18\x05\x20\x01(\x0cR\x05valueB\0\x12$\n\x04kind\x18\x06\x20\x02(\
+    \x0e2\x0e.MemAccessKindR\x04kindB\0:\0*&\n\rMemAccessKind\x12\x08\n\x04R\
+    EAD\x10\0\x12\t\n\x05WRITE\x10\x01\x1a\0B\0b\x06proto2\
+";
+
+static file_descriptor_proto_lazy: ::protobuf::rt::LazyV2<::protobuf::descriptor::FileDescriptorProto> = ::protobuf::rt::LazyV2::INIT;
+
+fn parse_descriptor_proto() -> ::protobuf::descriptor::FileDescriptorProto {
+    ::protobuf::Message::parse_from_bytes(file_descriptor_proto_data).unwrap()
+}
+
+pub fn file_descriptor_proto() -> &'static ::protobuf::descriptor::FileDescriptorProto {
+    file_descriptor_proto_lazy.get(|| {
+        parse_descriptor_proto()
+    })
+}
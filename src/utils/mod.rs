@@ -1 +1,6 @@
 pub mod tbb;
+pub mod memtrace;
+pub mod drcov;
+pub mod registers;
+pub mod export;
+pub mod svd;
@@ -0,0 +1,107 @@
+//! Architectural register tables
+//!
+//! A `RegisterSet` maps the names used by the SLEIGH specification of an
+//! architecture to the names expected by external tools (e.g. Tenet), and
+//! resolves them against a `Translator` to operands that can be read from a
+//! `PCodeState`.
+use fugue::bytes::Order;
+use fugue::ir::{
+    Translator,
+    il::pcode::Operand,
+};
+use fuguex::state::pcode::PCodeState;
+
+#[derive(Debug, Clone)]
+pub struct RegisterSet {
+    registers: Vec<(String, String)>,   // (name in the SLEIGH spec, name to display)
+    program_counter: String,            // Display name of the program counter
+}
+
+impl RegisterSet {
+    pub fn new<N: Into<String>>(program_counter: N) -> Self {
+        Self {
+            registers: Vec::new(),
+            program_counter: program_counter.into(),
+        }
+    }
+
+    pub fn add<N: Into<String>, D: Into<String>>(mut self, state_name: N, display_name: D) -> Self {
+        self.registers.push((state_name.into(), display_name.into()));
+        self
+    }
+
+    /// ARM (AArch32 and Cortex-M)
+    pub fn arm() -> Self {
+        let mut set = Self::new("pc");
+        for i in 0..13 {
+            set = set.add(format!("r{}", i), format!("r{}", i));
+        }
+        set.add("sp", "sp")
+            .add("lr", "lr")
+            .add("pc", "pc")
+    }
+
+    /// 32-bit x86
+    pub fn x86() -> Self {
+        ["EAX", "EBX", "ECX", "EDX", "ESI", "EDI", "EBP", "ESP", "EIP"].iter()
+            .fold(Self::new("eip"), |set, name| set.add(*name, name.to_lowercase()))
+    }
+
+    /// x86-64
+    pub fn x86_64() -> Self {
+        ["RAX", "RBX", "RCX", "RDX", "RSI", "RDI", "RBP", "RSP",
+         "R8", "R9", "R10", "R11", "R12", "R13", "R14", "R15", "RIP"].iter()
+            .fold(Self::new("rip"), |set, name| set.add(*name, name.to_lowercase()))
+    }
+
+    pub fn program_counter(&self) -> &str {
+        &self.program_counter
+    }
+
+    pub fn len(&self) -> usize {
+        self.registers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty()
+    }
+
+    /// Resolve the registers to operands
+    /// Return the name of the first register unknown to the translator on failure
+    pub fn resolve(&self, translator: &Translator) -> Result<Vec<(String, Operand)>, String> {
+        self.registers.iter()
+            .map(|(state_name, display_name)| {
+                translator.register_by_name(state_name)
+                    .map(|varnode| (display_name.clone(), Operand::from_varnode(translator, varnode)))
+                    .ok_or_else(|| state_name.clone())
+            })
+            .collect()
+    }
+}
+
+/// Read the raw bytes of a register, in the byte order of the state
+pub fn read_register<O: Order>(state: &PCodeState<u8, O>, register: &Operand) -> Option<Vec<u8>> {
+    state.with_operand_values(register, |values| values.to_vec()).ok()
+}
+
+/// Format a value stored in the byte order `O` as a hexadecimal number
+pub fn value_to_hex<O: Order>(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 + bytes.len() * 2);
+    hex.push_str("0x");
+    if O::ENDIAN.is_little() {
+        bytes.iter().rev().for_each(|b| hex.push_str(&format!("{:02x}", b)));
+    } else {
+        bytes.iter().for_each(|b| hex.push_str(&format!("{:02x}", b)));
+    }
+    hex
+}
+
+/// Interpret up to 8 bytes stored in the byte order `O` as an unsigned integer
+pub fn value_to_u64<O: Order>(bytes: &[u8]) -> u64 {
+    let bytes = &bytes[..bytes.len().min(8)];
+    if O::ENDIAN.is_little() {
+        bytes.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+    } else {
+        bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+    }
+}
@@ -0,0 +1,147 @@
+//! CMSIS-SVD register maps
+//!
+//! Only what is needed to name peripheral registers is read: the base
+//! address of each peripheral (following `derivedFrom`) and the name,
+//! offset and size of its registers. Clusters are flattened, register
+//! arrays (`dim`) are expanded.
+use std::collections::HashMap;
+use thiserror::Error;
+use roxmltree::{Document, Node};
+
+#[derive(Debug, Error)]
+pub enum SvdError {
+    #[error("Malformed XML: {0}")]
+    Xml(#[from] roxmltree::Error),
+    #[error("Invalid SVD: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SvdRegister {
+    pub peripheral: String,
+    pub name: String,
+    pub address: u64,
+    pub size: usize,    // In bytes
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name(name))
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|child| child.text()).map(str::trim)
+}
+
+/// SVD scaled non-negative integer: decimal, `0x` hexadecimal or `#` binary
+fn parse_number(text: &str) -> Result<u64, SvdError> {
+    let text = text.trim();
+    let parsed = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else if let Some(bin) = text.strip_prefix('#') {
+        u64::from_str_radix(&bin.replace(|c| c == 'x' || c == 'X', "0"), 2)
+    } else {
+        text.parse()
+    };
+    parsed.map_err(|_| SvdError::Invalid(format!("not a number: {}", text)))
+}
+
+fn number(node: Node, name: &str) -> Result<Option<u64>, SvdError> {
+    child_text(node, name).map(parse_number).transpose()
+}
+
+fn single_letter(text: &str) -> Option<char> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphabetic() => Some(c),
+        _ => None,
+    }
+}
+
+// Expand `dim` arrays: `NAME[%s]` or `NAME%s`, with `dimIndex` or 0..dim
+fn expand_dim(node: Node, name: &str, offset: u64) -> Result<Vec<(String, u64)>, SvdError> {
+    let dim = match number(node, "dim")? {
+        Some(dim) => dim,
+        None => return Ok(vec![(name.to_string(), offset)]),
+    };
+    let increment = number(node, "dimIncrement")?
+        .ok_or_else(|| SvdError::Invalid(format!("{}: dim without dimIncrement", name)))?;
+    let indices: Vec<String> = match child_text(node, "dimIndex") {
+        Some(indices) if indices.contains('-') && !indices.contains(',') => {
+            let (start, end) = indices.split_once('-').unwrap();
+            let (start, end) = (start.trim(), end.trim());
+            match (single_letter(start), single_letter(end)) {
+                // Letter ranges, e.g. `A-D` for GPIO ports
+                (Some(start), Some(end)) => (start..=end).map(|c| c.to_string()).collect(),
+                _ => {
+                    let (start, end) = (parse_number(start)?, parse_number(end)?);
+                    (start..=end).map(|i| i.to_string()).collect()
+                },
+            }
+        },
+        Some(indices) => indices.split(',').map(|index| index.trim().to_string()).collect(),
+        None => (0..dim).map(|i| i.to_string()).collect(),
+    };
+    Ok(indices.iter().enumerate()
+        .map(|(i, index)| (name.replace("[%s]", index).replace("%s", index), offset + i as u64 * increment))
+        .collect())
+}
+
+fn collect_registers(node: Node, peripheral: &str, base: u64, default_size: u64, prefix: &str, registers: &mut Vec<SvdRegister>) -> Result<(), SvdError> {
+    for element in node.children().filter(|child| child.is_element()) {
+        let is_cluster = element.has_tag_name("cluster");
+        if !is_cluster && !element.has_tag_name("register") {
+            continue;
+        }
+        let name = child_text(element, "name")
+            .ok_or_else(|| SvdError::Invalid(format!("{}: register without name", peripheral)))?;
+        let offset = number(element, "addressOffset")?
+            .ok_or_else(|| SvdError::Invalid(format!("{}.{}: no addressOffset", peripheral, name)))?;
+        let size = number(element, "size")?.unwrap_or(default_size);
+        for (name, offset) in expand_dim(element, name, offset)? {
+            let name = format!("{}{}", prefix, name);
+            if is_cluster {
+                collect_registers(element, peripheral, base + offset, size, &format!("{}_", name), registers)?;
+            } else {
+                registers.push(SvdRegister {
+                    peripheral: peripheral.to_string(),
+                    name,
+                    address: base + offset,
+                    size: (size / 8) as usize,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Registers of every peripheral of an SVD file, sorted by address
+pub fn parse_svd(text: &str) -> Result<Vec<SvdRegister>, SvdError> {
+    let document = Document::parse(text)?;
+    let device = document.root_element();
+    let default_size = number(device, "size")?.unwrap_or(32);
+    let peripherals = child(device, "peripherals")
+        .ok_or_else(|| SvdError::Invalid("no peripherals".to_string()))?;
+
+    let by_name: HashMap<&str, Node> = peripherals.children()
+        .filter(|child| child.has_tag_name("peripheral"))
+        .filter_map(|peripheral| child_text(peripheral, "name").map(|name| (name, peripheral)))
+        .collect();
+
+    let mut registers = Vec::new();
+    for (name, peripheral) in &by_name {
+        let base = number(*peripheral, "baseAddress")?
+            .ok_or_else(|| SvdError::Invalid(format!("{}: no baseAddress", name)))?;
+        // Derived peripherals share the registers of their parent, unless they define their own
+        let source = match (child(*peripheral, "registers"), peripheral.attribute("derivedFrom")) {
+            (Some(registers), _) => registers,
+            (None, Some(parent)) => by_name.get(parent)
+                .and_then(|parent| child(*parent, "registers"))
+                .ok_or_else(|| SvdError::Invalid(format!("{}: derived from unknown {}", name, parent)))?,
+            (None, None) => continue,
+        };
+        let size = number(*peripheral, "size")?.unwrap_or(default_size);
+        collect_registers(source, name, base, size, "", &mut registers)?;
+    }
+    registers.sort_by_key(|register| register.address);
+    Ok(registers)
+}